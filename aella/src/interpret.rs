@@ -1,5 +1,5 @@
-use comp_gen::Interpreter;
-use std::collections::HashMap;
+use comp_gen::{ruler::egg, Interpreter, RandomEnv, Sampler};
+use std::collections::{BTreeSet, HashMap};
 
 use crate::lang;
 
@@ -104,3 +104,100 @@ impl Interpreter for lang::Command {
         }
     }
 }
+
+impl RandomEnv for lang::Command {
+    type Lang = lang::Aella;
+
+    fn free_vars(expr: &egg::RecExpr<Self::Lang>) -> BTreeSet<egg::Symbol> {
+        // variables that are only ever assigned to aren't inputs
+        let targets: BTreeSet<egg::Id> = expr
+            .as_ref()
+            .iter()
+            .filter_map(|node| match node {
+                lang::Aella::Assign([rd, _])
+                | lang::Aella::AsmMov([rd, _])
+                | lang::Aella::AsmAdd([rd, _, _])
+                | lang::Aella::AsmSub([rd, _, _])
+                | lang::Aella::AsmSmull([rd, _, _])
+                | lang::Aella::AsmSdiv([rd, _, _]) => Some(*rd),
+                _ => None,
+            })
+            .collect();
+        expr.as_ref()
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !targets.contains(&egg::Id::from(*i)))
+            .filter_map(|(_, node)| match node {
+                lang::Aella::Var(sym) => Some(*sym),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn random_env(vars: &BTreeSet<egg::Symbol>, sampler: &mut Sampler) -> Self::Env {
+        vars.iter()
+            .map(|sym| (sym.to_string(), sampler.int()))
+            .collect()
+    }
+
+    fn restrict(env: &Self::Env, vars: &BTreeSet<egg::Symbol>) -> Self::Env {
+        env.iter()
+            .filter(|(var, _)| vars.contains(&egg::Symbol::from(var.as_str())))
+            .map(|(var, val)| (var.clone(), *val))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comp_gen::{differential_test, EnvConfig, ToRecExpr};
+    use crate::lang::{Command, NumExpr};
+
+    fn var(name: &str) -> Box<NumExpr> {
+        Box::new(NumExpr::Id(name.to_string()))
+    }
+
+    fn outputs(names: &[&str]) -> BTreeSet<egg::Symbol> {
+        names.iter().map(|name| egg::Symbol::from(*name)).collect()
+    }
+
+    #[test]
+    fn test_free_vars_skip_assigned() {
+        let prog = Command::Seq(vec![
+            Command::Assign("t".to_string(), NumExpr::Plus(var("a"), var("b"))),
+            Command::Assign("out".to_string(), NumExpr::Times(var("b"), var("c"))),
+        ]);
+        let mut expr = egg::RecExpr::default();
+        prog.to_recexpr(&mut expr);
+        assert_eq!(Command::free_vars(&expr), outputs(&["a", "b", "c"]));
+
+        let mut sampler = Sampler::new(&EnvConfig::default());
+        let env = Command::random_env(&outputs(&["a", "b"]), &mut sampler);
+        assert_eq!(env.len(), 2);
+        assert!(env.contains_key("a") && env.contains_key("b"));
+    }
+
+    #[test]
+    fn test_differential_equivalent() {
+        // the temporary only exists on one side
+        let lhs = Command::Seq(vec![
+            Command::Add("t".to_string(), *var("a"), *var("b")),
+            Command::Mov("out".to_string(), *var("t")),
+        ]);
+        let rhs = Command::Assign("out".to_string(), NumExpr::Plus(var("b"), var("a")));
+        let config = EnvConfig::default();
+        let res = differential_test(&lhs, &rhs, &outputs(&["out"]), 100, &config);
+        assert!(res.is_ok(), "{res:?}");
+    }
+
+    #[test]
+    fn test_differential_not_equivalent() {
+        let lhs = Command::Assign("out".to_string(), NumExpr::Sub(var("a"), var("b")));
+        let rhs = Command::Assign("out".to_string(), NumExpr::Sub(var("b"), var("a")));
+        let config = EnvConfig::default();
+        let cex = differential_test(&lhs, &rhs, &outputs(&["out"]), 100, &config).unwrap_err();
+        assert_ne!(cex.lhs.1["out"], cex.rhs.1["out"]);
+        assert_eq!(cex.lhs.1["out"], cex.env["a"] - cex.env["b"]);
+    }
+}
@@ -97,7 +97,7 @@ impl ruler::SynthLanguage for Aella {
 
     fn eval<'a, F>(&'a self, cvec_len: usize, mut get: F) -> ruler::CVec<Self>
     where
-        F: FnMut(&'a Id) -> &'a ruler::CVec<Self>,
+        F: FnMut(&'a Id) -> &'a [Option<Self::Constant>],
     {
         // println!("expr: {self}");
         let x = match self {
@@ -257,7 +257,7 @@ impl ruler::SynthLanguage for Aella {
         println!("cvec_len: {cvec_len}");
 
         // make a new egraph
-        let mut egraph = egg::EGraph::new(ruler::SynthAnalysis { cvec_len });
+        let mut egraph = egg::EGraph::new(ruler::SynthAnalysis::new(cvec_len));
 
         // add constants to the egraph
         for v in constants {
@@ -272,25 +272,26 @@ impl ruler::SynthLanguage for Aella {
             let free_id = egraph.add(Aella::Var(*var));
 
             // ints
-            egraph[free_id].data.cvec = (0..cvec_len / 2)
+            let mut cvec = (0..cvec_len / 2)
                 .map(|_| Value::random_env(&mut synth.rng, 0, 10))
                 .map(Some)
                 .collect::<Vec<_>>();
 
             // vars
-            egraph[free_id].data.cvec.extend(
+            cvec.extend(
                 (0..cvec_len / 2)
                     .map(|_| Value::random_env(&mut synth.rng, 1, 10))
                     .map(Some)
                     .collect::<Vec<_>>(),
             );
 
-            egraph[free_id].data.cvec.extend(
+            cvec.extend(
                 (0..cvec_len / 2)
                     .map(|_| Value::random_env(&mut synth.rng, 2, 10))
                     .map(Some)
                     .collect::<Vec<_>>(),
             );
+            egraph[free_id].data.cvec = cvec.into();
 
             // make the cvec use
             println!("cvec: {} {:?}", var, egraph[free_id].data.cvec);
@@ -335,10 +336,11 @@ impl ruler::SynthLanguage for Aella {
     }
 
     fn is_valid(
-        _synth: &mut ruler::Synthesizer<Self, ruler::Init>,
+        _synth: &ruler::Synthesizer<Self, ruler::Init>,
+        _rng: &mut Pcg32,
         lhs: &egg::Pattern<Self>,
         rhs: &egg::Pattern<Self>,
-    ) -> bool {
+    ) -> ruler::ValidationResult {
         // construct an environment for the variables
         // let mut env = std::collections::HashMap::default();
 
@@ -397,7 +399,7 @@ impl ruler::SynthLanguage for Aella {
         // eprintln!("rhs: {lvec:?} ={}= {rvec:?}", lvec == rvec);
         // panic!("env: {:?}", env);
 
-        true
+        ruler::ValidationResult::Valid(ruler::Validation::Unvalidated)
     }
 }
 
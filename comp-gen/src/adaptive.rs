@@ -0,0 +1,271 @@
+use std::{cell::RefCell, collections::HashMap, fs};
+
+use log::info;
+use ruler::egg;
+
+use crate::config::AdaptiveConfiguration;
+
+/// Factor a budget is raised by when a phase ran out of nodes without
+/// improving the cost.
+const DEFAULT_GROW: f64 = 2.0;
+/// Factor a budget is lowered by at most when a phase used less than it.
+const DEFAULT_SHRINK: f64 = 0.5;
+/// Budgets are never lowered below this many nodes.
+const DEFAULT_MIN_NODES: usize = 10_000;
+
+/// Node budgets for each phase that adapt to how the egraph grew the last
+/// time that phase ran. Phases are identified by their name, so a phase
+/// inside of a loop gets a new budget on every trip around the loop.
+/// Phases that haven't run yet use their configured limit. Budgets only
+/// live for one compile, see [NodeBudgets::reset].
+pub(crate) struct NodeBudgets {
+    grow: f64,
+    shrink: f64,
+    min_nodes: usize,
+    max_nodes: usize,
+    budgets: RefCell<HashMap<String, usize>>,
+}
+
+impl NodeBudgets {
+    /// `total_node_limit` is used as the largest budget if the config
+    /// doesn't specify one.
+    pub fn new(
+        config: &AdaptiveConfiguration,
+        total_node_limit: usize,
+    ) -> Self {
+        let max_nodes = config.max_nodes.unwrap_or(total_node_limit);
+        Self {
+            grow: config.grow.unwrap_or(DEFAULT_GROW).max(1.0),
+            shrink: config.shrink.unwrap_or(DEFAULT_SHRINK).clamp(0.0, 1.0),
+            min_nodes: config
+                .min_nodes
+                .unwrap_or(DEFAULT_MIN_NODES)
+                .min(max_nodes),
+            max_nodes,
+            budgets: RefCell::default(),
+        }
+    }
+
+    /// Forget the budgets learned so far, so that every compile starts from
+    /// the configured limits.
+    pub fn reset(&self) {
+        self.budgets.borrow_mut().clear();
+    }
+
+    /// A description of the settings (but not the current budgets), used
+    /// to tell apart compilers with different settings.
+    pub fn describe(&self) -> String {
+        format!(
+            "grow: {} shrink: {} min: {} max: {}",
+            self.grow, self.shrink, self.min_nodes, self.max_nodes
+        )
+    }
+
+    /// The node limit to use for `phase`. Phases that haven't run yet use
+    /// their configured limit. Both are kept between the smallest and the
+    /// largest budget.
+    pub fn limit(&self, phase: &str, configured: usize) -> usize {
+        self.budgets
+            .borrow()
+            .get(phase)
+            .copied()
+            .unwrap_or(configured)
+            .clamp(self.min_nodes, self.max_nodes)
+    }
+
+    /// Update the budget of `phase` after a run that used `limit` as its
+    /// node limit.
+    ///  - If the run hit the node limit without improving the cost, the
+    ///    egraph didn't have enough room, so we raise the budget.
+    ///  - If the run hit the node limit and improved the cost, the budget
+    ///    is fine as it is.
+    ///  - Otherwise the run stopped for some other reason, so we lower the
+    ///    budget to what the egraph would have grown to in one more
+    ///    iteration, at the rate it was growing at the end of the run.
+    pub fn update<L, N>(
+        &self,
+        phase: &str,
+        limit: usize,
+        runner: &egg::Runner<L, N, ()>,
+        improved: bool,
+    ) where
+        L: egg::Language,
+        N: egg::Analysis<L>,
+    {
+        let peak = runner.egraph.total_number_of_nodes();
+        let hit_limit =
+            matches!(runner.stop_reason, Some(egg::StopReason::NodeLimit(_)));
+        let new_limit = self.next_limit(
+            limit,
+            peak,
+            last_growth(runner),
+            hit_limit,
+            improved,
+        );
+
+        if new_limit != limit {
+            info!(
+                "Adjusting node budget of '{phase}': {limit} -> {new_limit} (peak: {peak})"
+            );
+        }
+        self.budgets
+            .borrow_mut()
+            .insert(phase.to_string(), new_limit);
+    }
+
+    /// The budget after a run with node limit `limit` that grew the egraph
+    /// to `peak` nodes, growing by a factor of `growth` in its last
+    /// iteration. See [NodeBudgets::update].
+    fn next_limit(
+        &self,
+        limit: usize,
+        peak: usize,
+        growth: f64,
+        hit_limit: bool,
+        improved: bool,
+    ) -> usize {
+        if hit_limit && !improved {
+            (limit as f64 * self.grow) as usize
+        } else if hit_limit {
+            limit
+        } else {
+            let growth = growth.clamp(1.0, self.grow);
+            let floor = limit as f64 * self.shrink;
+            ((peak as f64 * growth).max(floor) as usize).min(limit)
+        }
+        .clamp(self.min_nodes, self.max_nodes)
+    }
+}
+
+/// The ratio between the number of nodes in the egraph in the last two
+/// iterations of `runner`.
+fn last_growth<L, N>(runner: &egg::Runner<L, N, ()>) -> f64
+where
+    L: egg::Language,
+    N: egg::Analysis<L>,
+{
+    let nodes: Vec<usize> =
+        runner.iterations.iter().map(|it| it.egraph_nodes).collect();
+    match nodes.as_slice() {
+        [.., prev, last] if *prev > 0 => *last as f64 / *prev as f64,
+        _ => 1.0,
+    }
+}
+
+/// The resident memory of this process in megabytes. Only supported on
+/// Linux, returns `None` elsewhere.
+pub(crate) fn resident_memory_mb() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+/// Fails when the resident memory of this process is above `limit_mb`
+/// megabytes. Used as a runner hook, so that saturation stops before the
+/// next iteration.
+pub(crate) fn check_memory(limit_mb: usize) -> Result<(), String> {
+    match resident_memory_mb() {
+        Some(rss) if rss > limit_mb => Err(format!(
+            "Resident memory ({rss}MB) exceeded limit ({limit_mb}MB)"
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budgets() -> NodeBudgets {
+        let config = AdaptiveConfiguration {
+            grow: Some(2.0),
+            shrink: Some(0.5),
+            min_nodes: Some(100),
+            max_nodes: Some(10_000),
+        };
+        NodeBudgets::new(&config, 1_000_000)
+    }
+
+    #[test]
+    fn test_limit_is_clamped() {
+        let budgets = budgets();
+        assert_eq!(budgets.limit("a", 1_000), 1_000);
+        assert_eq!(budgets.limit("a", 10), 100);
+        assert_eq!(budgets.limit("a", 50_000), 10_000);
+    }
+
+    #[test]
+    fn test_budget_grows() {
+        let budgets = budgets();
+        // ran out of room without improving
+        assert_eq!(budgets.next_limit(1_000, 1_000, 1.5, true, false), 2_000);
+        // improved, so the budget was enough
+        assert_eq!(budgets.next_limit(1_000, 1_000, 1.5, true, true), 1_000);
+        // never above the largest budget
+        assert_eq!(budgets.next_limit(8_000, 8_000, 1.5, true, false), 10_000);
+    }
+
+    #[test]
+    fn test_budget_shrinks() {
+        let budgets = budgets();
+        // room for one more iteration at the last growth rate
+        assert_eq!(budgets.next_limit(1_000, 600, 1.5, false, false), 900);
+        // but at most by the shrink factor in one step
+        assert_eq!(budgets.next_limit(1_000, 100, 1.5, false, false), 500);
+        // and never below the smallest budget
+        assert_eq!(budgets.next_limit(150, 10, 1.0, false, false), 100);
+        // a budget is never raised when the limit wasn't hit
+        assert_eq!(budgets.next_limit(1_000, 900, 2.0, false, false), 1_000);
+    }
+
+    #[test]
+    fn test_update_only_touches_its_phase() {
+        let budgets = budgets();
+        let runner: egg::Runner<egg::SymbolLang, (), ()> =
+            egg::Runner::default()
+                .with_expr(&"(+ a b)".parse().unwrap())
+                .run(&[egg::rewrite!("comm"; "(+ ?a ?b)" => "(+ ?b ?a)")]);
+        assert!(matches!(
+            runner.stop_reason,
+            Some(egg::StopReason::Saturated)
+        ));
+
+        budgets.update("a", 1_000, &runner, false);
+        assert_eq!(budgets.limit("a", 1_000), 500);
+        assert_eq!(budgets.limit("b", 1_000), 1_000);
+        budgets.reset();
+        assert_eq!(budgets.limit("a", 1_000), 1_000);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_resident_memory() {
+        let rss = resident_memory_mb().unwrap();
+        assert!(check_memory(rss + 1_000_000).is_ok());
+        assert!(check_memory(0).is_err() || rss == 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_memory_ceiling_stops_runner() {
+        // any process uses more than zero megabytes, so the runner stops
+        // before its first iteration
+        let runner: egg::Runner<egg::SymbolLang, (), ()> =
+            egg::Runner::default()
+                .with_expr(&"(+ a b)".parse().unwrap())
+                .with_hook(|_| check_memory(0))
+                .run(&[egg::rewrite!("comm"; "(+ ?a ?b)" => "(+ ?b ?a)")]);
+        match &runner.stop_reason {
+            Some(egg::StopReason::Other(msg)) => {
+                assert!(msg.contains("exceeded limit"), "{msg}")
+            }
+            reason => panic!("unexpected stop reason: {reason:?}"),
+        }
+
+        // stopping early doesn't raise the budget
+        let budgets = budgets();
+        budgets.update("a", 1_000, &runner, false);
+        assert!(budgets.limit("a", 1_000) <= 1_000);
+    }
+}
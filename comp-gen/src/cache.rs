@@ -0,0 +1,448 @@
+use std::{
+    any::type_name,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use ruler::egg;
+
+use crate::{compiler, phases::Phase, CostMetrics, FromPattern};
+
+/// Name of the file that holds a cached result inside of its entry directory.
+const ENTRY_FILE: &str = "result.json";
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, the output of this is stable
+/// across Rust versions and platforms, so cache keys stay valid between
+/// builds.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Hash a length-prefixed string so that adjacent fields can't run
+    /// into each other.
+    fn field<S: AsRef<str>>(&mut self, s: S) {
+        let s = s.as_ref();
+        self.bytes(&(s.len() as u64).to_le_bytes());
+        self.bytes(s.as_bytes());
+    }
+
+    fn hex(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// A compile result read from the cache.
+pub(crate) struct CacheEntry<L: egg::Language> {
+    pub prog: egg::RecExpr<L>,
+    pub cost: String,
+    pub report: json::JsonValue,
+}
+
+impl<L, N, C> compiler::Compiler<L, N, C>
+where
+    L: egg::Language
+        + egg::FromOp
+        + Send
+        + Sync
+        + FromPattern
+        + std::fmt::Display
+        + 'static,
+    N: egg::Analysis<L> + Default + Clone,
+    C: CostMetrics<L, N>
+        + egg::CostFunction<L>
+        + Clone
+        + std::fmt::Debug
+        + 'static,
+{
+    /// Compute the cache key for compiling `prog` with this compiler. The
+    /// key covers the input program, the rules (names and patterns), the
+    /// rules selected by every phase along with the phase options, the
+    /// global limits, and the identity of the cost function (its type and
+    /// `Debug` representation). Rules with custom searchers or appliers are
+    /// only identified by their name.
+    pub(crate) fn cache_key(&self, prog: &egg::RecExpr<L>) -> String {
+        let mut h = Fnv::new();
+        h.field(prog.to_string());
+
+        h.field(self.rules.len().to_string());
+        for r in &self.rules {
+            h.field(r.name.as_str());
+            h.field(pattern_string(r.searcher.get_pattern_ast()));
+            h.field(pattern_string(r.applier.get_pattern_ast()));
+        }
+
+        self.hash_phase(&mut h, &self.phases);
+
+        h.field(format!(
+            "{} {} {} {} {:?} {:?}",
+            self.total_node_limit,
+            self.total_iter_limit,
+            self.timeout,
+            self.reuse_egraphs,
+            self.scheduler,
+            self.init_node
+        ));
+        h.field(
+            self.node_budgets
+                .as_ref()
+                .map(|b| b.describe())
+                .unwrap_or_default(),
+        );
+
+        h.field(type_name::<C>());
+        h.field(format!("{:?}", self.cost_fn));
+        h.hex()
+    }
+
+    /// Phase predicates are closures, so we hash the names of the rules
+    /// that they select instead.
+    fn hash_phase(&self, h: &mut Fnv, phase: &Phase<L, N, C>) {
+        match phase {
+            Phase::Single(single) => {
+                h.field("single");
+                h.field(format!(
+                    "{} {} {:?} {:?} {:?} {:?}",
+                    single.name,
+                    single.fresh_egraph,
+                    single.node_limit,
+                    single.iter_limit,
+                    single.timeout,
+                    single.scheduler
+                ));
+                let mut cost_fn = self.cost_fn.clone();
+                for r in &self.rules {
+                    if (single.select)(cost_fn.all(r)) {
+                        h.field(r.name.as_str());
+                    }
+                }
+            }
+            Phase::Loop {
+                phases,
+                loops,
+                timeout,
+            } => {
+                h.field(format!("loop {loops} {timeout:?} {}", phases.len()));
+                for p in phases {
+                    self.hash_phase(h, p);
+                }
+            }
+        }
+    }
+
+    /// Look for a cached result for `key`. Entries that can't be read, or
+    /// whose cost no longer matches the cost function, are treated as misses.
+    pub(crate) fn cache_lookup(
+        &self,
+        dir: &Path,
+        key: &str,
+    ) -> Option<CacheEntry<L>> {
+        let path = entry_path(dir, key);
+        let contents = fs::read_to_string(&path).ok()?;
+
+        let entry = json::parse(&contents)
+            .ok()
+            .filter(|data| data["key"].as_str() == Some(key))
+            .and_then(|data| {
+                let prog = data["prog"].as_str()?.parse().ok()?;
+                let cost = data["cost"].as_str()?.to_string();
+                Some(CacheEntry {
+                    prog,
+                    cost,
+                    report: data["report"].clone(),
+                })
+            });
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                warn!("Ignoring malformed cache entry {path:?}");
+                return None;
+            }
+        };
+
+        let cost = format!("{:?}", self.cost_fn.clone().cost_rec(&entry.prog));
+        if cost != entry.cost {
+            warn!(
+                "Ignoring cache entry {path:?}: cached cost {} but the program now costs {cost}",
+                entry.cost
+            );
+            return None;
+        }
+
+        info!("Using cached result from {path:?}");
+        Some(entry)
+    }
+
+    /// Store a result in the cache. The entry is written to a temporary file
+    /// first and then renamed, so that concurrent compiles never see a
+    /// partially written entry.
+    pub(crate) fn cache_store(
+        &self,
+        dir: &Path,
+        key: &str,
+        prog: &egg::RecExpr<L>,
+        cost: &C::Cost,
+        report: json::JsonValue,
+    ) -> io::Result<()> {
+        let entry_dir = dir.join(key);
+        fs::create_dir_all(&entry_dir)?;
+
+        let mut data = json::JsonValue::new_object();
+        data["key"] = key.into();
+        data["prog"] = prog.to_string().into();
+        data["cost"] = format!("{cost:?}").into();
+        data["report"] = report;
+
+        let tmp =
+            entry_dir.join(format!("{ENTRY_FILE}.{}", std::process::id()));
+        let mut file = File::create(&tmp)?;
+        data.write_pretty(&mut file, 2)?;
+        file.flush()?;
+        fs::rename(&tmp, entry_path(dir, key))?;
+
+        info!("Cached result in {entry_dir:?}");
+        Ok(())
+    }
+}
+
+/// Remove every entry from the cache directory `dir`. Only the entry
+/// directories, the ones named by a key that hold an entry file, are
+/// removed. `dir` itself and anything else in it are left alone.
+pub fn clear_cache(dir: &Path) -> io::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    info!("Clearing compile cache {dir:?}");
+    let mut removed = 0;
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        let is_key = item
+            .file_name()
+            .to_str()
+            .map(|name| {
+                name.len() == 16 && name.chars().all(|c| c.is_ascii_hexdigit())
+            })
+            .unwrap_or(false);
+        if is_key
+            && item.file_type()?.is_dir()
+            && item.path().join(ENTRY_FILE).is_file()
+        {
+            fs::remove_dir_all(item.path())?;
+            removed += 1;
+        }
+    }
+    info!("Removed {removed} cache entries");
+    Ok(())
+}
+
+/// The path of the entry for `key` in the cache directory `dir`.
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(key).join(ENTRY_FILE)
+}
+
+fn pattern_string<L: egg::Language + std::fmt::Display>(
+    pat: Option<&egg::PatternAst<L>>,
+) -> String {
+    pat.map(|p| p.to_string())
+        .unwrap_or_else(|| "<custom>".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compiler;
+
+    impl FromPattern for egg::SymbolLang {
+        fn from_pattern(pat: &egg::PatternAst<Self>) -> egg::RecExpr<Self> {
+            let nodes: Vec<egg::SymbolLang> = pat
+                .as_ref()
+                .iter()
+                .map(|node| match node {
+                    egg::ENodeOrVar::ENode(n) => n.clone(),
+                    egg::ENodeOrVar::Var(v) => {
+                        egg::SymbolLang::leaf(v.to_string())
+                    }
+                })
+                .collect();
+            nodes.into()
+        }
+    }
+
+    /// Counts nodes. Every rule is put into every phase.
+    #[derive(Debug, Clone)]
+    struct Size;
+
+    impl egg::CostFunction<egg::SymbolLang> for Size {
+        type Cost = usize;
+
+        fn cost<C>(&mut self, enode: &egg::SymbolLang, mut costs: C) -> usize
+        where
+            C: FnMut(egg::Id) -> usize,
+        {
+            enode.fold(1, |sum, id| sum + costs(id))
+        }
+    }
+
+    impl CostMetrics<egg::SymbolLang, ()> for Size {
+        fn cost_differential(
+            &mut self,
+            _r: &egg::Rewrite<egg::SymbolLang, ()>,
+        ) -> usize {
+            0
+        }
+
+        fn cost_average(
+            &mut self,
+            _r: &egg::Rewrite<egg::SymbolLang, ()>,
+        ) -> usize {
+            0
+        }
+    }
+
+    fn compiler(dir: &Path) -> Compiler<egg::SymbolLang, (), Size> {
+        let mut compiler = Compiler::with_cost_fn(Size);
+        compiler
+            .add_rules(
+                vec![egg::rewrite!("add-0"; "(+ ?a 0)" => "?a")].into_iter(),
+            )
+            .with_phase_builder(|pb| {
+                pb.build_single("all", |_| true);
+            })
+            .with_cache_dir(dir.to_path_buf());
+        compiler
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("comp-gen-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn test_fnv_is_stable() {
+        // reference values of 64-bit FNV-1a
+        let mut h = Fnv::new();
+        assert_eq!(h.hex(), "cbf29ce484222325");
+        h.bytes(b"a");
+        assert_eq!(h.hex(), "af63dc4c8601ec8c");
+
+        let mut h = Fnv::new();
+        h.bytes(b"foobar");
+        assert_eq!(h.hex(), "85944171f73967e8");
+    }
+
+    #[test]
+    fn test_fields_dont_run_together() {
+        let mut a = Fnv::new();
+        a.field("ab");
+        a.field("c");
+        let mut b = Fnv::new();
+        b.field("a");
+        b.field("bc");
+        assert_ne!(a.hex(), b.hex());
+    }
+
+    #[test]
+    fn test_clear_cache_only_removes_entries() {
+        let dir = std::env::temp_dir()
+            .join(format!("comp-gen-cache-test-{}", std::process::id()));
+        let key = "0123456789abcdef";
+        fs::create_dir_all(dir.join(key)).unwrap();
+        fs::write(entry_path(&dir, key), "{}").unwrap();
+        fs::create_dir_all(dir.join("fedcba9876543210")).unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        fs::write(dir.join("other").join(ENTRY_FILE), "{}").unwrap();
+        fs::write(dir.join("notes.txt"), "keep me").unwrap();
+
+        clear_cache(&dir).unwrap();
+
+        assert!(!dir.join(key).exists());
+        assert!(dir.join("fedcba9876543210").exists());
+        assert!(dir.join("other").join(ENTRY_FILE).exists());
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compile_cache_hit_and_miss() {
+        let dir = test_dir("compile-cache-test");
+        let prog: egg::RecExpr<egg::SymbolLang> = "(+ x 0)".parse().unwrap();
+        let mut compiler = compiler(&dir);
+
+        // a miss saturates, so the egraph still has the original program
+        let (cost, out, egraph) = compiler.compile(prog.clone());
+        assert_eq!((cost, out.to_string()), (1, "x".to_string()));
+        assert!(egraph.total_number_of_nodes() > 1);
+        assert!(entry_path(&dir, &compiler.cache_key(&prog)).is_file());
+        let reports = compiler.phase_reports();
+        assert_eq!(reports.len(), 1);
+
+        // a hit only adds the cached program, and restores the reports
+        let (cost, out, egraph) = compiler.compile(prog.clone());
+        assert_eq!((cost, out.to_string()), (1, "x".to_string()));
+        assert_eq!(egraph.total_number_of_nodes(), 1);
+        let cached = compiler.phase_reports();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0]["phase"], reports[0]["phase"]);
+        assert_eq!(cached[0]["cost"], reports[0]["cost"]);
+
+        // bypassing the cache saturates again
+        compiler.bypass_cache();
+        let (_, _, egraph) = compiler.compile(prog);
+        assert!(egraph.total_number_of_nodes() > 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_key_invalidation() {
+        let dir = test_dir("cache-key-test");
+        let prog: egg::RecExpr<egg::SymbolLang> = "(+ x 0)".parse().unwrap();
+        let key = compiler(&dir).cache_key(&prog);
+        assert_eq!(compiler(&dir).cache_key(&prog), key);
+
+        let other: egg::RecExpr<egg::SymbolLang> = "(+ y 0)".parse().unwrap();
+        assert_ne!(compiler(&dir).cache_key(&other), key);
+
+        let mut more_rules = compiler(&dir);
+        more_rules.add_rules(
+            vec![egg::rewrite!("mul-1"; "(* ?a 1)" => "?a")].into_iter(),
+        );
+        assert_ne!(more_rules.cache_key(&prog), key);
+
+        let mut other_limit = compiler(&dir);
+        other_limit.with_total_node_limit(1_000);
+        assert_ne!(other_limit.cache_key(&prog), key);
+
+        let mut other_phases = compiler(&dir);
+        other_phases.with_phase_builder(|pb| {
+            pb.build_single_w_opts(
+                "all",
+                |_| true,
+                true,
+                None,
+                None,
+                None,
+                None,
+            );
+        });
+        assert_ne!(other_phases.cache_key(&prog), key);
+
+        // changed rules also miss when compiling
+        let mut compiler = compiler(&dir);
+        compiler.compile(prog.clone());
+        more_rules.compile(prog.clone());
+        assert!(entry_path(&dir, &more_rules.cache_key(&prog)).is_file());
+        assert!(entry_path(&dir, &key).is_file());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use ruler::egg::rewrite as rw;
 use std::{
+    cell::{Cell, RefCell},
     fs::File,
     io::Write,
     path::{Path, PathBuf},
@@ -8,10 +9,12 @@ use std::{
 use ruler::egg;
 
 use crate::{
+    adaptive::NodeBudgets,
     config,
     cost::CostMetrics,
     phases::{self, Phase},
-    FromPattern,
+    snapshot::EGraphDump,
+    stats, FromPattern, MetricProjection,
 };
 
 /// An equality saturation based compiler.
@@ -38,6 +41,24 @@ pub struct Compiler<
     pub(crate) explanations: bool,
     pub(crate) scheduler: config::RuleSchedulerOpt,
     pub(crate) stats: Option<PathBuf>,
+    /// Only extract the best cost for the time series below this many nodes.
+    pub(crate) stats_extract_limit: usize,
+    pub(crate) snapshot: Option<config::SnapshotConfiguration>,
+    /// Number of snapshots taken so far. Used to order the snapshot files.
+    pub(crate) snapshot_count: Cell<usize>,
+    /// Writes the whole egraph in egg's serialized json with each snapshot,
+    /// see `with_egraph_dumps`.
+    pub(crate) egraph_dump: Option<EGraphDump<L, N>>,
+    /// Per-phase node limits, when they are adapted between runs.
+    pub(crate) node_budgets: Option<NodeBudgets>,
+    /// Resident memory ceiling in megabytes.
+    pub(crate) memory_limit: Option<usize>,
+    /// Directory of cached compile results.
+    pub(crate) cache_dir: Option<PathBuf>,
+    /// Recompute results even if they are cached.
+    pub(crate) bypass_cache: bool,
+    /// Stats for every phase that ran during the current compile.
+    pub(crate) phase_reports: RefCell<Vec<json::JsonValue>>,
 }
 
 impl<L, N, C> Compiler<L, N, C>
@@ -51,7 +72,7 @@ where
         + 'static,
     N: egg::Analysis<L> + Default + Clone,
     C: CostMetrics<L, N> + egg::CostFunction<L> + Clone,
-    <C as egg::CostFunction<L>>::Cost: PartialOrd<f64>,
+    <C as egg::CostFunction<L>>::Cost: MetricProjection,
 {
     /// Construct a compiler using `cost_fn` to distinguish
     /// between the `source` and `target` languages.
@@ -74,6 +95,15 @@ where
             explanations: false,
             scheduler: config::RuleSchedulerOpt::default(),
             stats: None,
+            stats_extract_limit: stats::DEFAULT_EXTRACT_LIMIT,
+            snapshot: None,
+            snapshot_count: Cell::new(0),
+            egraph_dump: None,
+            node_budgets: None,
+            memory_limit: None,
+            cache_dir: None,
+            bypass_cache: false,
+            phase_reports: RefCell::default(),
         }
     }
 
@@ -93,8 +123,27 @@ where
         let contents = std::fs::read_to_string(filename).unwrap();
         let data = json::parse(&contents).unwrap();
 
+        // rulesets from incremental synthesis list the rules that they
+        // inherited separately, we want those as well
         let mut rules = vec![];
-        for (idx, eq) in data["eqs"].members().enumerate() {
+        for (idx, eq) in data["eqs"]
+            .members()
+            .chain(data["inherited"].members())
+            .enumerate()
+        {
+            // guards are evaluated on the cvecs of ruler's analysis, which
+            // the compiler doesn't have
+            if !eq["guard"].is_null() {
+                log::warn!(
+                    "Skipping conditional rule {} ==> {} if {} {}",
+                    eq["lhs"],
+                    eq["rhs"],
+                    eq["guard"]["var"],
+                    eq["guard"]["pred"]
+                );
+                continue;
+            }
+
             let lpat_raw: egg::Pattern<L> =
                 eq["lhs"].as_str().unwrap().parse().unwrap();
             let rpat_raw: egg::Pattern<L> =
@@ -202,6 +251,15 @@ where
             .clone()
             .unwrap_or(config::RuleSchedulerOpt::default());
         self.stats = config.stats.clone();
+        self.stats_extract_limit = config
+            .stats_extract_limit
+            .unwrap_or(stats::DEFAULT_EXTRACT_LIMIT);
+        self.snapshot = config.snapshot.clone();
+        self.node_budgets = config
+            .adaptive
+            .as_ref()
+            .map(|adaptive| NodeBudgets::new(adaptive, self.total_node_limit));
+        self.memory_limit = config.memory_limit;
         self
     }
 
@@ -218,11 +276,75 @@ where
         self
     }
 
+    /// Write a per-iteration time series of egraph growth to `path`. The
+    /// file is csv if `path` ends in `.csv`, and json lines otherwise.
     pub fn with_stats_path(&mut self, path: PathBuf) -> &mut Self {
         self.stats = Some(path);
         self
     }
 
+    /// Only extract the best cost for the time series when the egraph has
+    /// at most `limit` nodes.
+    pub fn with_stats_extract_limit(&mut self, limit: usize) -> &mut Self {
+        self.stats_extract_limit = limit;
+        self
+    }
+
+    /// Dump the egraph to `dir` (as json and dot) after every phase. Use
+    /// `with_egraph_dumps` to also write egg's serialization of the egraph.
+    pub fn with_snapshots(&mut self, dir: PathBuf) -> &mut Self {
+        self.snapshot = Some(config::SnapshotConfiguration::new(dir));
+        self
+    }
+
+    /// Dump the egraph after every phase according to `snapshot`.
+    pub fn with_snapshot_config(
+        &mut self,
+        snapshot: config::SnapshotConfiguration,
+    ) -> &mut Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Adapt the node limit of every phase to how much the egraph grew the
+    /// last time that phase ran. Budgets are bounded by the total node
+    /// limit, so set that first.
+    pub fn with_adaptive_node_limits(
+        &mut self,
+        adaptive: &config::AdaptiveConfiguration,
+    ) -> &mut Self {
+        self.node_budgets =
+            Some(NodeBudgets::new(adaptive, self.total_node_limit));
+        self
+    }
+
+    /// Gracefully stop saturating once the resident memory of this process
+    /// exceeds `megabytes`.
+    pub fn with_memory_limit(&mut self, megabytes: usize) -> &mut Self {
+        self.memory_limit = Some(megabytes);
+        self
+    }
+
+    /// Cache compile results in `dir`. Compiling a program that was already
+    /// compiled with the same rules, phases and cost function returns the
+    /// cached result without running equality saturation.
+    pub fn with_cache_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.cache_dir = Some(dir);
+        self
+    }
+
+    /// Ignore cached results, but still store new results in the cache.
+    pub fn bypass_cache(&mut self) -> &mut Self {
+        self.bypass_cache = true;
+        self
+    }
+
+    /// Stats for every phase that ran during the last compile, as json. For
+    /// a cached result, these are the stats stored with it.
+    pub fn phase_reports(&self) -> Vec<json::JsonValue> {
+        self.phase_reports.borrow().clone()
+    }
+
     pub(crate) fn new_egraph(&self) -> egg::EGraph<L, N> {
         let mut egraph = if self.explanations {
             egg::EGraph::new(N::default()).with_explanations_enabled()
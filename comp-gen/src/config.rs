@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use ruler::egg;
 use serde::{Deserialize, Serialize};
 
-use crate::{phases, FromPattern};
+use crate::{phases, FromPattern, MetricProjection};
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -29,6 +29,63 @@ pub struct CompilerConfiguration {
     pub phase: PhaseConfiguration,
     pub scheduler: Option<RuleSchedulerOpt>,
     pub stats: Option<PathBuf>,
+    #[serde(default)]
+    pub stats_extract_limit: Option<usize>,
+    #[serde(default)]
+    pub snapshot: Option<SnapshotConfiguration>,
+    #[serde(default)]
+    pub adaptive: Option<AdaptiveConfiguration>,
+    /// Stop saturating when the resident memory of the process goes above
+    /// this many megabytes.
+    #[serde(default)]
+    pub memory_limit: Option<usize>,
+}
+
+/// Configuration for adapting the node limit of each phase based on how
+/// the egraph grew in earlier runs of that phase.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdaptiveConfiguration {
+    /// Factor to raise a budget by when a phase hits its node limit
+    /// without improving the cost.
+    pub grow: Option<f64>,
+    /// Smallest factor a budget is lowered by in one step.
+    pub shrink: Option<f64>,
+    /// Lower bound for budgets.
+    pub min_nodes: Option<usize>,
+    /// Upper bound for budgets. Defaults to `total_node_limit`.
+    pub max_nodes: Option<usize>,
+}
+
+/// Configuration for dumping egraph snapshots after phases.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotConfiguration {
+    /// Directory that the `.json` and `.dot` snapshots are written to.
+    pub dir: PathBuf,
+    /// Names of the phases to snapshot. Snapshots every phase when `None`.
+    pub phases: Option<Vec<String>>,
+    /// How many steps away from the extracted program to keep eclasses.
+    pub radius: Option<usize>,
+    /// Egraphs with more eclasses than this are cut down.
+    pub max_classes: Option<usize>,
+}
+
+impl SnapshotConfiguration {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            phases: None,
+            radius: None,
+            max_classes: None,
+        }
+    }
+
+    /// Should we take a snapshot after the phase called `name`?
+    pub fn includes(&self, name: &str) -> bool {
+        self.phases
+            .as_ref()
+            .map(|phases| phases.iter().any(|p| p == name))
+            .unwrap_or(true)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -58,7 +115,7 @@ impl<
         C: egg::CostFunction<L>,
     > Into<phases::Phase<L, N, C>> for PhaseConfiguration
 where
-    <C as egg::CostFunction<L>>::Cost: PartialOrd<f64>,
+    <C as egg::CostFunction<L>>::Cost: MetricProjection,
 {
     fn into(self) -> phases::Phase<L, N, C> {
         let mut pb = phases::PhaseBuilder::<L, N, C>::default();
@@ -82,10 +139,11 @@ where
                         // check all the conditions, if a condition doesn't exist
                         // default to true for that check
                         move |cm| {
-                            cd_low.map(|l| cm.cd > l).unwrap_or(true)
-                                && cd_high.map(|h| cm.cd <= h).unwrap_or(true)
-                                && ca_low.map(|l| cm.ca > l).unwrap_or(true)
-                                && ca_high.map(|h| cm.ca <= h).unwrap_or(true)
+                            let (cd, ca) = (cm.cd.project(), cm.ca.project());
+                            cd_low.map(|l| cd > l).unwrap_or(true)
+                                && cd_high.map(|h| cd <= h).unwrap_or(true)
+                                && ca_low.map(|l| ca > l).unwrap_or(true)
+                                && ca_high.map(|h| ca <= h).unwrap_or(true)
                         },
                         fresh_egraph.unwrap_or(false),
                         node_limit,
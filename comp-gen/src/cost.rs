@@ -1,4 +1,4 @@
-use std::marker::PhantomData;
+use std::{fmt, marker::PhantomData};
 
 use ruler::egg;
 
@@ -24,6 +24,100 @@ where
     }
 }
 
+/// Maps a cost onto the scalar that phase bounds are compared against.
+/// Rules are selected for a phase using the projection of their
+/// `CostMetric`, while extraction still uses the full ordering of the cost.
+pub trait MetricProjection {
+    fn project(&self) -> f64;
+}
+
+macro_rules! impl_projection {
+    ($($t:ty),*) => {
+        $(impl MetricProjection for $t {
+            fn project(&self) -> f64 {
+                *self as f64
+            }
+        })*
+    };
+}
+
+impl MetricProjection for f64 {
+    fn project(&self) -> f64 {
+        *self
+    }
+}
+
+impl_projection!(f32, usize, u64, u32, isize, i64, i32);
+
+/// Combines two cost functions into one that orders programs by `primary`
+/// first, and uses `secondary` to break ties. Phases select rules using
+/// only the primary cost.
+#[derive(Debug, Clone)]
+pub struct Lexicographic<A, B> {
+    pub primary: A,
+    pub secondary: B,
+}
+
+impl<A, B> Lexicographic<A, B> {
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+/// The cost computed by `Lexicographic`. The derived ordering compares the
+/// primary cost first.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct LexCost<A, B>(pub A, pub B);
+
+impl<A: MetricProjection, B> MetricProjection for LexCost<A, B> {
+    fn project(&self) -> f64 {
+        self.0.project()
+    }
+}
+
+impl<A: fmt::Display, B: fmt::Display> fmt::Display for LexCost<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.0, self.1)
+    }
+}
+
+impl<L, A, B> egg::CostFunction<L> for Lexicographic<A, B>
+where
+    L: egg::Language,
+    A: egg::CostFunction<L>,
+    B: egg::CostFunction<L>,
+{
+    type Cost = LexCost<A::Cost, B::Cost>;
+
+    fn cost<C>(&mut self, enode: &L, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(egg::Id) -> Self::Cost,
+    {
+        let primary = self.primary.cost(enode, |id| costs(id).0);
+        let secondary = self.secondary.cost(enode, |id| costs(id).1);
+        LexCost(primary, secondary)
+    }
+}
+
+impl<L, N, A, B> CostMetrics<L, N> for Lexicographic<A, B>
+where
+    L: egg::Language + FromPattern,
+    N: egg::Analysis<L>,
+    A: CostMetrics<L, N>,
+    B: CostMetrics<L, N>,
+{
+    fn cost_differential(&mut self, r: &egg::Rewrite<L, N>) -> Self::Cost {
+        LexCost(
+            self.primary.cost_differential(r),
+            self.secondary.cost_differential(r),
+        )
+    }
+
+    fn cost_average(&mut self, r: &egg::Rewrite<L, N>) -> Self::Cost {
+        LexCost(self.primary.cost_average(r), self.secondary.cost_average(r))
+    }
+}
+
 /// Find the depth of a `RecExpr` starting at a particular `root` node.
 #[allow(unused)]
 pub fn depth<L: egg::Language, I: Into<egg::Id>>(
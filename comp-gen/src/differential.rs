@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeSet,
+    panic::{self, AssertUnwindSafe},
+};
+
+use rand::{distributions::Uniform, Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use ruler::egg;
+use serde::{Deserialize, Serialize};
+
+use crate::{RandomEnv, ToRecExpr};
+
+/// Controls how random environments are generated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvConfig {
+    /// Seed for the random number generator.
+    pub seed: u64,
+    /// Smallest integer value to generate (inclusive).
+    pub min: i64,
+    /// Largest integer value to generate (inclusive).
+    pub max: i64,
+    /// Probability of picking an edge case instead of a uniform value.
+    pub edge_bias: f64,
+    /// Values that are interesting to test on. `min` and `max` are always
+    /// considered edge cases.
+    pub edge_cases: Vec<i64>,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            min: -100,
+            max: 100,
+            edge_bias: 0.2,
+            edge_cases: vec![0, 1, -1],
+        }
+    }
+}
+
+/// Draws values for random environments according to an `EnvConfig`.
+pub struct Sampler {
+    rng: Pcg32,
+    min: i64,
+    max: i64,
+    edge_bias: f64,
+    edge_cases: Vec<i64>,
+}
+
+impl Sampler {
+    pub fn new(config: &EnvConfig) -> Self {
+        assert!(config.min <= config.max, "empty value range");
+        let mut edge_cases: Vec<i64> = config
+            .edge_cases
+            .iter()
+            .copied()
+            .chain([config.min, config.max])
+            .filter(|x| config.min <= *x && *x <= config.max)
+            .collect();
+        edge_cases.sort_unstable();
+        edge_cases.dedup();
+
+        Self {
+            rng: Pcg32::seed_from_u64(config.seed),
+            min: config.min,
+            max: config.max,
+            edge_bias: config.edge_bias.clamp(0.0, 1.0),
+            edge_cases,
+        }
+    }
+
+    /// Draw an integer in `[min, max]`, picking one of the edge cases with
+    /// probability `edge_bias`.
+    pub fn int(&mut self) -> i64 {
+        if self.rng.gen_bool(self.edge_bias) {
+            self.edge_cases[self.rng.gen_range(0, self.edge_cases.len())]
+        } else {
+            self.rng.sample(Uniform::new_inclusive(self.min, self.max))
+        }
+    }
+
+    /// Access the underlying random number generator, for languages that
+    /// need to draw values that aren't integers.
+    pub fn rng(&mut self) -> &mut Pcg32 {
+        &mut self.rng
+    }
+}
+
+/// An input on which two programs disagree.
+#[derive(Debug, Clone)]
+pub struct Counterexample<E, R> {
+    /// The environment the programs were started in.
+    pub env: E,
+    /// Result and final environment of the first program. The result is the
+    /// panic message if the program panicked, e.g. on a division by zero.
+    pub lhs: (Result<R, String>, E),
+    /// Result and final environment of the second program.
+    pub rhs: (Result<R, String>, E),
+}
+
+/// Evaluate `prog` in `env`, catching panics of the interpreter.
+fn eval_catching<P: RandomEnv>(
+    prog: &P,
+    env: &mut P::Env,
+) -> Result<P::Res, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| prog.eval_with_env(env))).map_err(
+        |e| {
+            e.downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "panicked".to_string())
+        },
+    )
+}
+
+/// Evaluate `lhs` and `rhs` on `n` random environments that bind the free
+/// variables of both programs. Two runs agree when they produce the same
+/// result and the same values for the variables in `outputs`, or when both
+/// of them panic. Other variables, like scratch registers, are free to
+/// differ. Returns the first environment on which they disagree.
+pub fn differential_test<P>(
+    lhs: &P,
+    rhs: &P,
+    outputs: &BTreeSet<egg::Symbol>,
+    n: usize,
+    config: &EnvConfig,
+) -> Result<(), Counterexample<P::Env, P::Res>>
+where
+    P: RandomEnv + ToRecExpr<P::Lang>,
+    P::Env: Clone + PartialEq,
+    P::Res: PartialEq,
+{
+    let mut vars = BTreeSet::new();
+    for prog in [lhs, rhs] {
+        let mut expr = egg::RecExpr::default();
+        prog.to_recexpr(&mut expr);
+        vars.extend(P::free_vars(&expr));
+    }
+
+    let mut sampler = Sampler::new(config);
+    for _ in 0..n {
+        let env = P::random_env(&vars, &mut sampler);
+
+        let mut lhs_env = env.clone();
+        let lhs_res = eval_catching(lhs, &mut lhs_env);
+        let mut rhs_env = env.clone();
+        let rhs_res = eval_catching(rhs, &mut rhs_env);
+
+        let agree = match (&lhs_res, &rhs_res) {
+            (Ok(l), Ok(r)) => {
+                l == r
+                    && P::restrict(&lhs_env, outputs)
+                        == P::restrict(&rhs_env, outputs)
+            }
+            (Err(_), Err(_)) => true,
+            _ => false,
+        };
+        if !agree {
+            return Err(Counterexample {
+                env,
+                lhs: (lhs_res, lhs_env),
+                rhs: (rhs_res, rhs_env),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampler_stays_in_range() {
+        let config = EnvConfig {
+            min: -3,
+            max: 5,
+            edge_cases: vec![0, 42],
+            ..EnvConfig::default()
+        };
+        let sampler = Sampler::new(&config);
+        // edge cases outside of the range are dropped
+        assert_eq!(sampler.edge_cases, vec![-3, 0, 5]);
+
+        let mut sampler = Sampler::new(&config);
+        for _ in 0..1000 {
+            let x = sampler.int();
+            assert!(-3 <= x && x <= 5, "{x} is out of range");
+        }
+    }
+
+    #[test]
+    fn test_sampler_is_deterministic() {
+        let config = EnvConfig {
+            seed: 7,
+            ..EnvConfig::default()
+        };
+        let mut a = Sampler::new(&config);
+        let mut b = Sampler::new(&config);
+        let xs: Vec<i64> = (0..100).map(|_| a.int()).collect();
+        let ys: Vec<i64> = (0..100).map(|_| b.int()).collect();
+        assert_eq!(xs, ys);
+    }
+
+    #[test]
+    fn test_sampler_edge_bias() {
+        let config = EnvConfig {
+            edge_bias: 1.0,
+            ..EnvConfig::default()
+        };
+        let mut sampler = Sampler::new(&config);
+        for _ in 0..100 {
+            assert!([-100, -1, 0, 1, 100].contains(&sampler.int()));
+        }
+    }
+}
@@ -1,14 +1,24 @@
+mod adaptive;
+mod cache;
 mod compiler;
 pub mod config;
 mod cost;
+mod differential;
 mod phases;
 mod run;
+mod snapshot;
 mod stats;
 
+use std::collections::BTreeSet;
+
 use ruler::egg;
 
+pub use cache::clear_cache;
 pub use compiler::Compiler;
-pub use cost::{CostMetric, CostMetrics};
+pub use cost::{
+    CostMetric, CostMetrics, LexCost, Lexicographic, MetricProjection,
+};
+pub use differential::{differential_test, Counterexample, EnvConfig, Sampler};
 pub use phases::PhaseBuilder;
 pub use ruler;
 
@@ -22,6 +32,26 @@ pub trait Interpreter {
     }
 }
 
+/// Companion to `Interpreter` that knows how to build environments for a
+/// program. Used to run programs on random inputs, see `differential_test`.
+pub trait RandomEnv: Interpreter {
+    type Lang: egg::Language;
+
+    /// The variables that a program reads from its environment.
+    fn free_vars(expr: &egg::RecExpr<Self::Lang>) -> BTreeSet<egg::Symbol>;
+
+    /// Build an environment that binds every variable in `vars` to a value
+    /// drawn from `sampler`.
+    fn random_env(
+        vars: &BTreeSet<egg::Symbol>,
+        sampler: &mut Sampler,
+    ) -> Self::Env;
+
+    /// The part of `env` that binds the variables in `vars`. Used to compare
+    /// the outputs of two programs without looking at their temporaries.
+    fn restrict(env: &Self::Env, vars: &BTreeSet<egg::Symbol>) -> Self::Env;
+}
+
 /// Recursively translate some type `T` into a `egg::RecExpr`.
 pub trait ToRecExpr<T> {
     fn to_recexpr(&self, expr: &mut egg::RecExpr<T>) -> egg::Id;
@@ -1,14 +1,19 @@
-use std::time::{Duration, Instant};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use log::{debug, info, warn};
 use ruler::egg;
 
 use crate::{
-    compiler,
+    adaptive, compiler,
     config::RuleSchedulerOpt,
     phases::{Phase, SinglePhase},
-    stats::{EggStats, Stats},
-    CostMetrics, FromPattern,
+    snapshot::Snapshot,
+    stats::{self, IterationRow, Stats},
+    CostMetrics, FromPattern, MetricProjection,
 };
 
 pub struct EqSatResult<
@@ -37,7 +42,7 @@ where
         + Clone
         + std::fmt::Debug
         + 'static,
-    <C as egg::CostFunction<L>>::Cost: PartialOrd<f64>,
+    <C as egg::CostFunction<L>>::Cost: MetricProjection,
     <C as egg::CostFunction<L>>::Cost: PartialEq,
 {
     fn equality_saturate(
@@ -102,21 +107,27 @@ where
             time_left
         };
 
+        // use the adapted budget for this phase if we have one
+        let node_limit = phase.node_limit.unwrap_or(self.total_node_limit);
+        let node_limit = match &self.node_budgets {
+            Some(budgets) => budgets.limit(&phase.name, node_limit),
+            None => node_limit,
+        };
+
         info!("Making runner");
         let iter_cost_fn = self.cost_fn.clone();
         let mut runner: egg::Runner<L, N, ()> =
             egg::Runner::new(Default::default())
                 .with_egraph(egraph)
                 .with_expr(&old_prog)
-                .with_node_limit(
-                    phase.node_limit.unwrap_or(self.total_node_limit),
-                )
+                .with_node_limit(node_limit)
                 .with_iter_limit(
                     phase.iter_limit.unwrap_or(self.total_iter_limit),
                 )
                 .with_time_limit(timeout);
         debug!("Time left: {:?}", time_left);
         debug!("Using timeout: {:?}", timeout);
+        debug!("Using node limit: {node_limit}");
 
         runner = if self.debug {
             runner.with_hook(move |runner| {
@@ -144,9 +155,43 @@ where
             }
         };
 
-        // Add EggStats hook if we have a stats path
-        runner = if let Some(_) = self.stats {
-            runner.with_hook(EggStats::density())
+        // Record a row of the time series at the start of every iteration
+        // if we have a stats path.
+        let series: Rc<RefCell<Vec<IterationRow>>> = Rc::default();
+        runner = if self.stats.is_some() {
+            let series = series.clone();
+            let phase_name = phase.name.clone();
+            let series_cost_fn = self.cost_fn.clone();
+            let extract_limit = self.stats_extract_limit;
+            runner.with_hook(move |runner| {
+                // only extract when the egraph is small enough for it to be cheap
+                let best_cost =
+                    if runner.egraph.total_number_of_nodes() <= extract_limit {
+                        let extractor = egg::Extractor::new(
+                            &runner.egraph,
+                            series_cost_fn.clone(),
+                        );
+                        Some(format!(
+                            "{:?}",
+                            extractor.find_best_cost(runner.roots[0])
+                        ))
+                    } else {
+                        None
+                    };
+                series.borrow_mut().push(IterationRow::from_runner(
+                    &phase_name,
+                    runner,
+                    best_cost,
+                ));
+                Ok(())
+            })
+        } else {
+            runner
+        };
+
+        // stop before the next iteration if we are using too much memory
+        runner = if let Some(limit) = self.memory_limit {
+            runner.with_hook(move |_| adaptive::check_memory(limit))
         } else {
             runner
         };
@@ -168,6 +213,46 @@ where
 
         debug!("Egraph size: {}", runner.egraph.total_size());
 
+        if let Some(budgets) = &self.node_budgets {
+            budgets.update(&phase.name, node_limit, &runner, cost < old_cost);
+        }
+
+        // record the final state of the egraph and flush the time series
+        if let Some(path) = &self.stats {
+            let mut series = series.borrow_mut();
+            if series.last().map(|row| row.iteration)
+                != Some(runner.iterations.len())
+            {
+                series.push(IterationRow::from_runner(
+                    &phase.name,
+                    &runner,
+                    Some(format!("{cost:?}")),
+                ));
+            }
+            if let Err(e) = stats::append_series(path, &series) {
+                warn!("Failed to write stats to {path:?}: {e}");
+            }
+        }
+
+        // dump the egraph if we were asked to snapshot this phase
+        if let Some(snapshot) = &self.snapshot {
+            if snapshot.includes(&phase.name) {
+                let n = self.snapshot_count.get();
+                self.snapshot_count.set(n + 1);
+                let name = format!("{n:03}-{}", phase.name);
+                if let Err(e) = Snapshot::new(
+                    snapshot,
+                    &runner.egraph,
+                    self.cost_fn.clone(),
+                    runner.roots[0],
+                )
+                .write(&snapshot.dir, &name, self.egraph_dump)
+                {
+                    warn!("Failed to write snapshot '{name}': {e}");
+                }
+            }
+        }
+
         // Report some stats about this phase
         let stats = Stats::from_runner(
             &phase,
@@ -177,6 +262,7 @@ where
             cost.clone(),
         );
         stats.report();
+        self.phase_reports.borrow_mut().push(stats.to_json());
 
         EqSatResult {
             cost,
@@ -237,12 +323,53 @@ where
         eqsat
     }
 
+    /// Compile `prog`, returning the cost of the best program found, the
+    /// program, and the final egraph. Results are cached if a cache
+    /// directory is set. On a cache hit, equality saturation doesn't run, so
+    /// the egraph only contains the cached program, and `phase_reports` are
+    /// the reports of the compile that produced it.
     pub fn compile(
         &mut self,
         prog: egg::RecExpr<L>,
     ) -> (C::Cost, egg::RecExpr<L>, egg::EGraph<L, N>) {
         log::debug!("Phase config: {:#?}", self.phases);
         self.generate_rule_histogram();
+        self.phase_reports.borrow_mut().clear();
+        if let Some(budgets) = &self.node_budgets {
+            budgets.reset();
+        }
+
+        // return the cached result if we have already compiled this program.
+        // dry runs don't produce results worth caching. saturation doesn't
+        // run on a hit, so no stats series or snapshots are written for it.
+        let cache_key = self
+            .cache_dir
+            .as_ref()
+            .filter(|_| !self.dry_run)
+            .map(|_| self.cache_key(&prog));
+        if let (Some(dir), Some(key)) = (&self.cache_dir, &cache_key) {
+            if self.bypass_cache {
+                info!("Bypassing compile cache");
+            } else if let Some(entry) = self.cache_lookup(dir, key) {
+                info!("Cached report: {}", entry.report.pretty(2));
+                if self.stats.is_some() || self.snapshot.is_some() {
+                    info!("Skipping stats and snapshots for the cached result");
+                }
+                if let json::JsonValue::Array(reports) = entry.report {
+                    *self.phase_reports.borrow_mut() = reports;
+                }
+                let mut egraph = self.new_egraph();
+                egraph.add_expr(&entry.prog);
+                let cost = self.cost_fn.cost_rec(&entry.prog);
+                return (cost, entry.prog, egraph);
+            }
+        }
+
+        if let Some(path) = &self.stats {
+            if let Err(e) = stats::init_series(path) {
+                warn!("Failed to create stats file {path:?}: {e}");
+            }
+        }
 
         // initialize eqsat to the default egraph and the
         // program that we were given
@@ -254,6 +381,17 @@ where
         };
 
         let eqsat = self.run_phase(&self.phases, eqsat);
+
+        if let (Some(dir), Some(key)) = (&self.cache_dir, &cache_key) {
+            let report =
+                json::JsonValue::Array(self.phase_reports.borrow().clone());
+            if let Err(e) =
+                self.cache_store(dir, key, &eqsat.prog, &eqsat.cost, report)
+            {
+                warn!("Failed to write to cache {dir:?}: {e}");
+            }
+        }
+
         (eqsat.cost, eqsat.prog, eqsat.egraph)
     }
 }
@@ -0,0 +1,310 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use ruler::egg;
+
+use crate::{
+    compiler::Compiler, config::SnapshotConfiguration, CostMetrics,
+    FromPattern, MetricProjection,
+};
+
+/// Writes an egraph in egg's serialized json.
+pub(crate) type EGraphDump<L, N> =
+    fn(&egg::EGraph<L, N>, &mut dyn Write) -> io::Result<()>;
+
+fn dump_egraph<L, N>(
+    egraph: &egg::EGraph<L, N>,
+    out: &mut dyn Write,
+) -> io::Result<()>
+where
+    L: egg::Language + serde::Serialize,
+    N: egg::Analysis<L> + serde::Serialize,
+    N::Data: serde::Serialize,
+{
+    serde_json::to_writer(out, egraph)?;
+    Ok(())
+}
+
+impl<L, N, C> Compiler<L, N, C>
+where
+    L: egg::Language
+        + egg::FromOp
+        + Send
+        + Sync
+        + FromPattern
+        + std::fmt::Display
+        + serde::Serialize
+        + 'static,
+    N: egg::Analysis<L> + Default + Clone + serde::Serialize,
+    N::Data: serde::Serialize,
+    C: CostMetrics<L, N> + egg::CostFunction<L> + Clone,
+    <C as egg::CostFunction<L>>::Cost: MetricProjection,
+{
+    /// Also write the whole egraph in egg's serialized json with every
+    /// snapshot, as long as it isn't cut down to the eclasses near the
+    /// extracted program. This needs a serializable language and analysis,
+    /// so it is separate from `with_snapshots`.
+    pub fn with_egraph_dumps(&mut self) -> &mut Self {
+        self.egraph_dump = Some(dump_egraph::<L, N>);
+        self
+    }
+}
+
+/// How many hops away from the extracted program to include by default.
+const DEFAULT_RADIUS: usize = 1;
+/// The default maximum number of eclasses kept in a snapshot.
+const DEFAULT_MAX_CLASSES: usize = 500;
+
+/// A view of an egraph that is restricted to the eclasses that are near
+/// the program extracted from `root`. Used to dump an egraph to disk
+/// after a phase so that it can be inspected.
+pub(crate) struct Snapshot<'a, L, N, C>
+where
+    L: egg::Language,
+    N: egg::Analysis<L>,
+    C: egg::CostFunction<L>,
+{
+    egraph: &'a egg::EGraph<L, N>,
+    extractor: egg::Extractor<'a, C, L, N>,
+    root: egg::Id,
+    classes: BTreeSet<egg::Id>,
+}
+
+impl<'a, L, N, C> Snapshot<'a, L, N, C>
+where
+    L: egg::Language + std::fmt::Display,
+    N: egg::Analysis<L>,
+    C: egg::CostFunction<L>,
+{
+    pub fn new(
+        config: &SnapshotConfiguration,
+        egraph: &'a egg::EGraph<L, N>,
+        cost_fn: C,
+        root: egg::Id,
+    ) -> Self {
+        let extractor = egg::Extractor::new(egraph, cost_fn);
+        let root = egraph.find(root);
+        let max_classes = config.max_classes.unwrap_or(DEFAULT_MAX_CLASSES);
+
+        let classes = if egraph.number_of_classes() <= max_classes {
+            // small egraphs are kept whole
+            egraph.classes().map(|cls| cls.id).collect()
+        } else {
+            Self::neighborhood(
+                egraph,
+                &extractor,
+                root,
+                config.radius.unwrap_or(DEFAULT_RADIUS),
+                max_classes,
+            )
+        };
+
+        Self {
+            egraph,
+            extractor,
+            root,
+            classes,
+        }
+    }
+
+    /// Collect the eclasses of the best program rooted at `root`, and then
+    /// grow that set by following the children of every enode for `radius`
+    /// steps, stopping once we have `max_classes` eclasses.
+    fn neighborhood(
+        egraph: &egg::EGraph<L, N>,
+        extractor: &egg::Extractor<C, L, N>,
+        root: egg::Id,
+        radius: usize,
+        max_classes: usize,
+    ) -> BTreeSet<egg::Id> {
+        let mut keep = BTreeSet::new();
+        let mut todo = vec![root];
+        while let Some(id) = todo.pop() {
+            let id = egraph.find(id);
+            if keep.insert(id) {
+                todo.extend(extractor.find_best_node(id).children());
+            }
+        }
+
+        let mut frontier: Vec<egg::Id> = keep.iter().copied().collect();
+        'grow: for _ in 0..radius {
+            let mut next = vec![];
+            for id in frontier {
+                for node in &egraph[id].nodes {
+                    for child in node.children() {
+                        if keep.len() >= max_classes {
+                            break 'grow;
+                        }
+                        let child = egraph.find(*child);
+                        if keep.insert(child) {
+                            next.push(child);
+                        }
+                    }
+                }
+            }
+            frontier = next;
+        }
+        keep
+    }
+
+    /// Summarize the snapshot as json. Eclasses are keyed by their id, and
+    /// each eclass lists its enodes with their children (as eclass ids),
+    /// the index of the best enode, and the best cost for the eclass.
+    /// Children that fall outside of the snapshot are listed in `pruned`.
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut classes = json::JsonValue::new_object();
+        for &id in &self.classes {
+            let best = self.extractor.find_best_node(id);
+            let mut nodes = json::JsonValue::new_array();
+            let mut best_idx = json::JsonValue::Null;
+            for (i, node) in self.egraph[id].nodes.iter().enumerate() {
+                if node == best {
+                    best_idx = i.into();
+                }
+                let mut children = json::JsonValue::new_array();
+                for child in node.children() {
+                    children
+                        .push(usize::from(self.egraph.find(*child)))
+                        .unwrap();
+                }
+                let mut n = json::JsonValue::new_object();
+                n["op"] = node.to_string().into();
+                n["children"] = children;
+                nodes.push(n).unwrap();
+            }
+
+            let mut cls = json::JsonValue::new_object();
+            cls["best_cost"] =
+                format!("{:?}", self.extractor.find_best_cost(id)).into();
+            cls["best_node"] = best_idx;
+            cls["nodes"] = nodes;
+            classes[usize::from(id).to_string()] = cls;
+        }
+
+        let mut pruned = json::JsonValue::new_array();
+        for id in self.pruned_children() {
+            pruned.push(usize::from(id)).unwrap();
+        }
+
+        let mut data = json::JsonValue::new_object();
+        data["root"] = usize::from(self.root).into();
+        data["total_classes"] = self.egraph.number_of_classes().into();
+        data["total_nodes"] = self.egraph.total_number_of_nodes().into();
+        data["classes"] = classes;
+        data["pruned"] = pruned;
+        data
+    }
+
+    /// Render the snapshot as a Graphviz dot graph. Every eclass is a
+    /// cluster labelled with its id and best cost; the best enode of each
+    /// eclass is drawn in bold.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph egraph {\n");
+        out.push_str("  compound=true\n");
+        out.push_str("  clusterrank=local\n");
+
+        for &id in &self.classes {
+            let best = self.extractor.find_best_node(id);
+            let cost = format!("{:?}", self.extractor.find_best_cost(id));
+            out.push_str(&format!("  subgraph cluster_{} {{\n", id));
+            out.push_str("    style=dotted\n");
+            out.push_str(&format!(
+                "    label=\"#{} cost: {}\"\n",
+                id,
+                escape(&cost)
+            ));
+            for (i, node) in self.egraph[id].nodes.iter().enumerate() {
+                let style = if node == best { ",style=bold" } else { "" };
+                out.push_str(&format!(
+                    "    \"{}.{}\"[label=\"{}\"{}]\n",
+                    id,
+                    i,
+                    escape(&node.to_string()),
+                    style
+                ));
+            }
+            out.push_str("  }\n");
+        }
+
+        for &id in &self.classes {
+            for (i, node) in self.egraph[id].nodes.iter().enumerate() {
+                for child in node.children() {
+                    let child = self.egraph.find(*child);
+                    if self.classes.contains(&child) {
+                        out.push_str(&format!(
+                            "  \"{}.{}\" -> \"{}.0\" [lhead=cluster_{}]\n",
+                            id, i, child, child
+                        ));
+                    }
+                }
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// Eclasses that are referenced from the snapshot, but not included in it.
+    fn pruned_children(&self) -> BTreeSet<egg::Id> {
+        self.classes
+            .iter()
+            .flat_map(|&id| self.egraph[id].nodes.iter())
+            .flat_map(|node| node.children())
+            .map(|child| self.egraph.find(*child))
+            .filter(|child| !self.classes.contains(child))
+            .collect()
+    }
+
+    /// Write the summary of the eclasses near the extracted program to
+    /// `<dir>/<name>.summary.json` and `<dir>/<name>.dot`. If the egraph
+    /// wasn't cut down, it is also written to `<dir>/<name>.json` with
+    /// `dump`, when there is one.
+    pub fn write(
+        &self,
+        dir: &Path,
+        name: &str,
+        dump: Option<EGraphDump<L, N>>,
+    ) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+
+        if let Some(dump) = dump {
+            if self.classes.len() == self.egraph.number_of_classes() {
+                let mut egraph_file = io::BufWriter::new(File::create(
+                    dir.join(format!("{name}.json")),
+                )?);
+                dump(self.egraph, &mut egraph_file)?;
+                egraph_file.flush()?;
+            } else {
+                log::info!(
+                    "Not dumping the whole egraph for snapshot '{name}', it \
+                     has more than {} eclasses",
+                    self.classes.len()
+                );
+            }
+        }
+
+        let mut json_file =
+            File::create(dir.join(format!("{name}.summary.json")))?;
+        self.to_json().write_pretty(&mut json_file, 2)?;
+
+        let mut dot_file = File::create(dir.join(format!("{name}.dot")))?;
+        write!(dot_file, "{}", self.to_dot())?;
+
+        log::info!(
+            "Wrote snapshot '{name}' with {} / {} eclasses to {dir:?}",
+            self.classes.len(),
+            self.egraph.number_of_classes()
+        );
+        Ok(())
+    }
+}
+
+/// Escape double quotes so that a string can be used in a dot label.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -1,41 +1,126 @@
 use log::info;
 use ruler::egg;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
 
 use crate::{phases::SinglePhase, FromPattern};
 // use statrs::statistics::Statistics;
 
-#[derive(Default)]
-pub struct EggStats;
+/// The default node limit below which we extract the best cost on every
+/// iteration of the time series.
+pub(crate) const DEFAULT_EXTRACT_LIMIT: usize = 100_000;
 
-impl EggStats {
-    pub fn density<L: egg::Language, N: egg::Analysis<L>>(
-    ) -> impl FnMut(&mut egg::Runner<L, N>) -> Result<(), String> + 'static
-    {
-        |runner: &mut egg::Runner<L, N>| {
-            let s1 = plotlib::repr::Histogram::from_slice(
-                &runner
-                    .egraph
-                    .classes()
-                    .map(|cls| cls.nodes.len() as f64)
-                    .collect::<Vec<_>>(),
-                plotlib::repr::HistogramBins::Count(30),
-            );
+/// One row of the per-iteration time series. A row describes the state of
+/// the egraph after `iteration` iterations of `phase`.
+#[derive(Debug, Clone)]
+pub struct IterationRow {
+    pub phase: String,
+    pub iteration: usize,
+    pub nodes: usize,
+    pub classes: usize,
+    pub memo: usize,
+    pub applied: usize,
+    pub elapsed: f64,
+    pub best_cost: Option<String>,
+}
+
+impl IterationRow {
+    const HEADER: &'static str =
+        "phase,iteration,nodes,classes,memo,applied,elapsed,best_cost";
+
+    pub fn from_runner<L: egg::Language, N: egg::Analysis<L>>(
+        phase: &str,
+        runner: &egg::Runner<L, N, ()>,
+        best_cost: Option<String>,
+    ) -> Self {
+        Self {
+            phase: phase.to_string(),
+            iteration: runner.iterations.len(),
+            nodes: runner.egraph.total_number_of_nodes(),
+            classes: runner.egraph.number_of_classes(),
+            memo: runner.egraph.total_size(),
+            applied: runner
+                .iterations
+                .last()
+                .map(|iter| iter.applied.values().sum())
+                .unwrap_or(0),
+            elapsed: runner.iterations.iter().map(|iter| iter.total_time).sum(),
+            best_cost,
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            csv_escape(&self.phase),
+            self.iteration,
+            self.nodes,
+            self.classes,
+            self.memo,
+            self.applied,
+            self.elapsed,
+            self.best_cost
+                .as_deref()
+                .map(csv_escape)
+                .unwrap_or_default()
+        )
+    }
 
-            let v = plotlib::view::ContinuousView::new()
-                .add(s1)
-                .x_label("Count")
-                .y_label("Bins");
+    fn to_json(&self) -> json::JsonValue {
+        let mut row = json::JsonValue::new_object();
+        row["phase"] = self.phase.clone().into();
+        row["iteration"] = self.iteration.into();
+        row["nodes"] = self.nodes.into();
+        row["classes"] = self.classes.into();
+        row["memo"] = self.memo.into();
+        row["applied"] = self.applied.into();
+        row["elapsed"] = self.elapsed.into();
+        row["best_cost"] = self.best_cost.clone().into();
+        row
+    }
+}
+
+/// Quote a csv field if it contains anything that would break the row.
+fn csv_escape(s: &str) -> String {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// The time series is written as csv when the path ends in `.csv` and
+/// as json lines otherwise.
+fn is_csv(path: &Path) -> bool {
+    path.extension().map(|ext| ext == "csv").unwrap_or(false)
+}
+
+/// Create (or truncate) the time series file at `path`.
+pub(crate) fn init_series(path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    if is_csv(path) {
+        writeln!(file, "{}", IterationRow::HEADER)?;
+    }
+    Ok(())
+}
 
-            log::debug!(
-                "\n{}",
-                plotlib::page::Page::single(&v)
-                    .dimensions(80, 80)
-                    .to_text()
-                    .unwrap()
-            );
-            Ok(())
+/// Append `rows` to the time series file at `path`.
+pub(crate) fn append_series(
+    path: &Path,
+    rows: &[IterationRow],
+) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    for row in rows {
+        if is_csv(path) {
+            writeln!(file, "{}", row.to_csv())?;
+        } else {
+            writeln!(file, "{}", row.to_json().dump())?;
         }
     }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -82,6 +167,21 @@ where
         }
     }
 
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut data = json::JsonValue::new_object();
+        data["phase"] = self.phase_name.clone().into();
+        data["rules"] = self.rules.into();
+        data["stop_reason"] = format!("{:?}", self.stop_reason).into();
+        data["iterations"] = self.iterations.into();
+        data["nodes"] = self.egraph_total_nodes.into();
+        data["classes"] = self.egraph_total_classes.into();
+        data["memo"] = self.egraph_total_size.into();
+        data["old_cost"] = format!("{:?}", self.old_cost).into();
+        data["cost"] = format!("{:?}", self.cost).into();
+        data["time"] = self.total_time.into();
+        data
+    }
+
     pub fn report(&self) {
         info!("  Runner report");
         info!("  =============");
@@ -234,3 +234,42 @@ impl comp_gen::CostMetrics<VecLang, ()> for VecCostFn {
         }
     }
 }
+
+/// Counts the nodes in a program. Used to break ties between programs
+/// that `VecCostFn` considers equally good, preferring the smaller one.
+#[derive(Clone, Debug, Default)]
+pub struct NodeCount;
+
+impl egg::CostFunction<VecLang> for NodeCount {
+    type Cost = f64;
+
+    fn cost<C>(&mut self, enode: &VecLang, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(egg::Id) -> Self::Cost,
+    {
+        enode.fold(1., |sum, id| sum + costs(id))
+    }
+}
+
+impl comp_gen::CostMetrics<VecLang, ()> for NodeCount {
+    fn cost_differential(&mut self, r: &DiosRwrite) -> f64 {
+        match (r.searcher.get_pattern_ast(), r.applier.get_pattern_ast()) {
+            (Some(lhs), Some(rhs)) => {
+                self.cost_rec(&VecLang::from_pattern(lhs))
+                    - self.cost_rec(&VecLang::from_pattern(rhs))
+            }
+            _ => 0.,
+        }
+    }
+
+    fn cost_average(&mut self, r: &DiosRwrite) -> f64 {
+        match (r.searcher.get_pattern_ast(), r.applier.get_pattern_ast()) {
+            (Some(lhs), Some(rhs)) => {
+                (self.cost_rec(&VecLang::from_pattern(lhs))
+                    + self.cost_rec(&VecLang::from_pattern(rhs)))
+                    / 2.
+            }
+            _ => 0.,
+        }
+    }
+}
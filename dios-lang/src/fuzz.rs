@@ -5,6 +5,7 @@ use comp_gen::{
     ruler::{egg, SynthLanguage},
 };
 use itertools::Itertools;
+use rand_pcg::Pcg32;
 
 use crate::{
     lang,
@@ -13,7 +14,8 @@ use crate::{
 
 pub trait FuzzEquals: ruler::SynthLanguage {
     fn fuzz_equals(
-        synth: &mut ruler::Synthesizer<Self, ruler::Init>,
+        synth: &ruler::Synthesizer<Self, ruler::Init>,
+        rng: &mut Pcg32,
         lhs: &egg::Pattern<Self>,
         rhs: &egg::Pattern<Self>,
         _debug: bool,
@@ -22,7 +24,8 @@ pub trait FuzzEquals: ruler::SynthLanguage {
 
 impl FuzzEquals for lang::VecLang {
     fn fuzz_equals(
-        synth: &mut ruler::Synthesizer<Self, ruler::Init>,
+        synth: &ruler::Synthesizer<Self, ruler::Init>,
+        rng: &mut Pcg32,
         lhs: &egg::Pattern<Self>,
         rhs: &egg::Pattern<Self>,
         _debug: bool,
@@ -74,7 +77,7 @@ impl FuzzEquals for lang::VecLang {
         for cvec in env.values_mut() {
             cvec.extend(
                 lang::Value::sample_vec(
-                    &mut synth.rng,
+                    rng,
                     -100,
                     100,
                     synth.lang_config.vector_size,
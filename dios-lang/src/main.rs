@@ -14,10 +14,18 @@ mod synthesis;
 use crate::{desugar::Desugar, letify::Letify};
 use anyhow::Context;
 use argh::FromArgs;
-use comp_gen::ruler::egg;
+use comp_gen::{
+    ruler::{self, egg, SynthLanguage},
+    MetricProjection,
+};
 pub use error::Res;
 use log::info;
-use std::{fs, io::Write, path::PathBuf, process};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+};
 
 /// Generate and run an automatically generated compiler
 /// for the Diospyros vector language.
@@ -34,6 +42,14 @@ enum Commands {
     Synth(SynthOpts),
     /// compile an input program using a ruleset
     Compile(CompileOpts),
+    /// remove rules that are derivable from the rest of a ruleset
+    Minimize(MinimizeOpts),
+    /// check which rules of two rulesets can be derived from the other
+    Derive(DeriveOpts),
+    /// export a ruleset to the format of another rewriting tool
+    Export(ExportOpts),
+    /// merge the rulesets of sharded synthesis runs
+    Merge(MergeOpts),
 }
 
 #[derive(Clone, FromArgs)]
@@ -50,6 +66,40 @@ pub struct SynthOpts {
     /// path to a chkpt file
     #[argh(option, from_str_fn(read_path))]
     checkpoint: Option<PathBuf>,
+
+    /// resume from the newest checkpoint in the checkpoint directory
+    #[argh(switch)]
+    resume: bool,
+
+    /// ruleset from an earlier run to start from (can be repeated)
+    #[argh(option, from_str_fn(read_path))]
+    prior: Vec<PathBuf>,
+
+    /// split synthesis across this many worker processes and merge their
+    /// rulesets
+    #[argh(option, default = "1")]
+    shards: usize,
+
+    /// only synthesize this shard, given as `index/count`
+    #[argh(option)]
+    shard: Option<ruler::Shard>,
+
+    /// don't minimize the merged ruleset of a sharded run
+    #[argh(switch)]
+    no_minimize: bool,
+
+    /// iteration limit for deriving a rule when minimizing a sharded run
+    #[argh(option, default = "3")]
+    merge_iter_limit: usize,
+
+    /// node limit for deriving a rule when minimizing a sharded run
+    #[argh(option, default = "100_000")]
+    merge_node_limit: usize,
+
+    /// time limit in seconds for deriving a rule when minimizing a sharded
+    /// run
+    #[argh(option, default = "10")]
+    merge_time_limit: u64,
 }
 
 /// Read a `synthesis::DiosConfig` from a path (represented as a `&str`).
@@ -107,6 +157,142 @@ struct CompileOpts {
     /// cost fun
     #[argh(option)]
     costfn: String,
+
+    /// dump egraph snapshots after each phase into this directory
+    #[argh(option, from_str_fn(read_path))]
+    snapshot_dir: Option<PathBuf>,
+
+    /// cache compile results in this directory
+    #[argh(option, from_str_fn(read_path))]
+    cache_dir: Option<PathBuf>,
+
+    /// recompile even if the result is cached
+    #[argh(switch)]
+    bypass_cache: bool,
+
+    /// remove all cached results before compiling
+    #[argh(switch)]
+    clear_cache: bool,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "minimize")]
+/// Minimize options.
+struct MinimizeOpts {
+    /// ruleset to minimize
+    #[argh(positional)]
+    input: String,
+
+    /// where to write the minimized ruleset
+    #[argh(option)]
+    output: String,
+
+    /// where to write the report of removed rules
+    #[argh(option, default = "String::from(\"minimize_report.json\")")]
+    report: String,
+
+    /// iteration limit for deriving a rule
+    #[argh(option, default = "3")]
+    iter_limit: usize,
+
+    /// node limit for deriving a rule
+    #[argh(option, default = "100_000")]
+    node_limit: usize,
+
+    /// time limit in seconds for deriving a rule
+    #[argh(option, default = "10")]
+    time_limit: u64,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "derive")]
+/// Derive options.
+struct DeriveOpts {
+    /// first ruleset
+    #[argh(positional)]
+    rules1: String,
+
+    /// second ruleset
+    #[argh(positional)]
+    rules2: String,
+
+    /// where to write the derivability report
+    #[argh(option)]
+    output: String,
+
+    /// vector width to desugar the rules to
+    #[argh(option)]
+    vector_width: usize,
+
+    /// the rulesets are already desugared
+    #[argh(switch)]
+    pre_desugared: bool,
+
+    /// iteration limit for deriving a rule
+    #[argh(option, default = "3")]
+    iter_limit: usize,
+
+    /// node limit for deriving a rule
+    #[argh(option, default = "100_000")]
+    node_limit: usize,
+
+    /// time limit in seconds for deriving a rule
+    #[argh(option, default = "10")]
+    time_limit: u64,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "export")]
+/// Export options.
+struct ExportOpts {
+    /// ruleset to export
+    #[argh(positional)]
+    input: PathBuf,
+
+    /// where to write the exported rules
+    #[argh(option, from_str_fn(read_path))]
+    output: PathBuf,
+
+    /// format to export to: egglog or text
+    #[argh(option, default = "ruler::ExportFormat::Egglog")]
+    format: ruler::ExportFormat,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "merge")]
+/// Merge options.
+struct MergeOpts {
+    /// rulesets written by the shards
+    #[argh(positional)]
+    inputs: Vec<PathBuf>,
+
+    /// where to write the merged ruleset
+    #[argh(option, from_str_fn(read_path))]
+    output: PathBuf,
+
+    /// don't minimize the merged ruleset
+    #[argh(switch)]
+    no_minimize: bool,
+
+    /// rejected rule cache written by a shard (can be repeated)
+    #[argh(option, from_str_fn(read_path))]
+    rejected: Vec<PathBuf>,
+
+    /// where to write the union of the rejected rule caches
+    #[argh(option, from_str_fn(read_path))]
+    rejected_output: Option<PathBuf>,
+
+    /// iteration limit for deriving a rule
+    #[argh(option, default = "3")]
+    iter_limit: usize,
+
+    /// node limit for deriving a rule
+    #[argh(option, default = "100_000")]
+    node_limit: usize,
+
+    /// time limit in seconds for deriving a rule
+    #[argh(option, default = "10")]
+    time_limit: u64,
 }
 
 fn read_path(path: &str) -> Result<PathBuf, String> {
@@ -124,13 +310,123 @@ fn read_compiler_config(
 
 /// Synthesize a new ruleset using `Ruler`.
 fn synth(synth_opts: SynthOpts) -> Res<()> {
-    let report = synthesis::run(
-        synth_opts.config.unwrap_or_default(),
-        synth_opts.checkpoint,
-    )?;
-    let file = std::fs::File::create(&synth_opts.output)
-        .unwrap_or_else(|_| panic!("Failed to open '{}'", &synth_opts.output));
-    serde_json::to_writer_pretty(file, &report).expect("failed to write json");
+    if synth_opts.shards > 1 {
+        return synth_sharded(synth_opts);
+    }
+
+    let mut config = synth_opts.config.unwrap_or_default();
+    config.ruler_config.prior_rulesets.extend(synth_opts.prior);
+    if synth_opts.shard.is_some() {
+        config.ruler_config.shard = synth_opts.shard;
+    }
+    let report =
+        synthesis::run(config, synth_opts.checkpoint, synth_opts.resume)?;
+    // write the report atomically, so that a partially written report
+    // never replaces an earlier one
+    ruler::write_json_atomic(Path::new(&synth_opts.output), &report)
+        .with_context(|| format!("write {:?}", synth_opts.output))?;
+    Ok(())
+}
+
+/// Synthesize a ruleset with one worker process per shard, and merge the
+/// rulesets of the workers into `synth_opts.output`. The workers write their
+/// config and ruleset next to the output.
+fn synth_sharded(synth_opts: SynthOpts) -> Res<()> {
+    anyhow::ensure!(
+        synth_opts.checkpoint.is_none(),
+        "sharded runs can only be resumed with --resume"
+    );
+    let mut config = synth_opts.config.unwrap_or_default();
+    config.ruler_config.prior_rulesets.extend(synth_opts.prior);
+
+    let exe = std::env::current_exe().context("find the dios-lang binary")?;
+    let shard_path = |shard: ruler::Shard, ext: &str| -> PathBuf {
+        PathBuf::from(format!(
+            "{}.shard-{}.{ext}",
+            synth_opts.output, shard.index
+        ))
+    };
+
+    // every worker gets its own config, so that they don't overwrite each
+    // other's checkpoints, partial rulesets, events and rejected rules. each
+    // worker starts from a copy of the rejected rule cache, and their caches
+    // are merged back into it.
+    let mut outputs = vec![];
+    let mut rejected = vec![];
+    for shard in ruler::Shard::all(synth_opts.shards) {
+        let mut shard_config = config.clone();
+        let params = &mut shard_config.ruler_config;
+        params.shard = Some(shard);
+        params.checkpoint_dir = Some(
+            params
+                .checkpoint_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join(format!("shard-{}", shard.index)),
+        );
+        params.partial_output = params
+            .partial_output
+            .as_ref()
+            .map(|_| shard_path(shard, "partial.json"));
+        params.events_file = params
+            .events_file
+            .as_ref()
+            .map(|_| shard_path(shard, "events.jsonl"));
+        if let Some(cache) = &params.rejected_cache {
+            let shard_cache = shard_path(shard, "rejected.json");
+            if cache.exists() {
+                fs::copy(cache, &shard_cache).with_context(|| {
+                    format!("copy {cache:?} to {shard_cache:?}")
+                })?;
+            }
+            rejected.push(shard_cache.clone());
+            params.rejected_cache = Some(shard_cache);
+        }
+        let path = shard_path(shard, "config.json");
+        ruler::write_json_atomic(&path, &shard_config)
+            .with_context(|| format!("write {path:?}"))?;
+        outputs.push(shard_path(shard, "json"));
+    }
+
+    ruler::run_shards(synth_opts.shards, |shard| {
+        let mut cmd = process::Command::new(&exe);
+        cmd.arg("synth")
+            .arg(shard_path(shard, "json"))
+            .arg("--config")
+            .arg(shard_path(shard, "config.json"));
+        if synth_opts.resume {
+            cmd.arg("--resume");
+        }
+        cmd
+    })?;
+
+    let report = ruler::merge::<lang::VecLang>(&ruler::MergeParams {
+        inputs: outputs,
+        rejected,
+        rejected_output: config.ruler_config.rejected_cache.clone(),
+        minimize: !synth_opts.no_minimize,
+        iter_limit: synth_opts.merge_iter_limit,
+        node_limit: synth_opts.merge_node_limit,
+        time_limit: synth_opts.merge_time_limit,
+    })?;
+    ruler::write_json_atomic(Path::new(&synth_opts.output), &report)
+        .with_context(|| format!("write {:?}", synth_opts.output))?;
+    Ok(())
+}
+
+/// Merge the rulesets of sharded synthesis runs into one ruleset.
+fn merge(opts: MergeOpts) -> Res<()> {
+    let report = ruler::merge::<lang::VecLang>(&ruler::MergeParams {
+        inputs: opts.inputs,
+        rejected: opts.rejected,
+        rejected_output: opts.rejected_output,
+        minimize: !opts.no_minimize,
+        iter_limit: opts.iter_limit,
+        node_limit: opts.node_limit,
+        time_limit: opts.time_limit,
+    })?;
+    ruler::write_json_atomic(&opts.output, &report)
+        .with_context(|| format!("write {:?}", opts.output))?;
     Ok(())
 }
 
@@ -172,12 +468,16 @@ fn compile(opts: CompileOpts) -> Res<()> {
     // log::debug!("input: {}", prog.pretty(80));
 
     let mut compiler: comp_gen::Compiler<lang::VecLang, (), _> =
-        comp_gen::Compiler::with_cost_fn(match opts.costfn.as_str() {
-            "alternative" => cost::VecCostFn::alternative(),
-            "dios" => cost::VecCostFn::dios(),
-            "accurate" => cost::VecCostFn::accurate(),
-            _ => panic!("Not a valid cost function."),
-        });
+        comp_gen::Compiler::with_cost_fn(comp_gen::Lexicographic::new(
+            match opts.costfn.as_str() {
+                "alternative" => cost::VecCostFn::alternative(),
+                "dios" => cost::VecCostFn::dios(),
+                "accurate" => cost::VecCostFn::accurate(),
+                _ => panic!("Not a valid cost function."),
+            },
+            // break ties between programs of equal cost by their size
+            cost::NodeCount,
+        ));
 
     // add rules to compiler
     compiler.with_init_node(lang::VecLang::Const(lang::Value::Int(0)));
@@ -196,13 +496,29 @@ fn compile(opts: CompileOpts) -> Res<()> {
         .add_rules(
             handwritten::build_litvec_rule(opts.vector_width).into_iter(),
         )
-        .output_rule_distribution("rule_distribution.csv", |x| x);
+        .output_rule_distribution("rule_distribution.csv", |x| x.project());
 
     // load configuration
     if let Some(config) = &opts.config {
         compiler.with_config(config);
     }
 
+    // snapshots from the config or the flag include the whole egraph
+    compiler.with_egraph_dumps();
+    if let Some(dir) = &opts.snapshot_dir {
+        compiler.with_snapshots(dir.clone());
+    }
+
+    if let Some(dir) = &opts.cache_dir {
+        if opts.clear_cache {
+            comp_gen::clear_cache(dir)?;
+        }
+        compiler.with_cache_dir(dir.clone());
+        if opts.bypass_cache {
+            compiler.bypass_cache();
+        }
+    }
+
     // compiler.with_explanations();
     let (cost, prog, mut _eg) = compiler.compile(prog);
     info!("cost: {cost}");
@@ -237,6 +553,70 @@ fn compile(opts: CompileOpts) -> Res<()> {
     Ok(())
 }
 
+/// Greedily remove the rules of a ruleset that can be derived from the
+/// remaining rules.
+fn minimize(opts: MinimizeOpts) -> Res<()> {
+    ruler::minimize::<lang::VecLang>(ruler::MinimizeParams {
+        input: opts.input,
+        out: opts.output,
+        report: opts.report,
+        iter_limit: opts.iter_limit,
+        node_limit: opts.node_limit,
+        time_limit: opts.time_limit,
+    })?;
+    Ok(())
+}
+
+/// Check which rules of each ruleset can be derived from the rules of the
+/// other, after desugaring both of them to the same vector width.
+fn derive(opts: DeriveOpts) -> Res<()> {
+    let read = |path: &str| -> Res<Vec<ruler::Pair<lang::VecLang>>> {
+        let eqs = ruler::read_ruleset::<lang::VecLang>(Path::new(path))
+            .with_context(|| format!("read ruleset {path:?}"))?;
+        Ok(eqs
+            .into_iter()
+            .map(|eq| {
+                let (lhs, rhs) = if opts.pre_desugared {
+                    (eq.lhs, eq.rhs)
+                } else {
+                    (
+                        eq.lhs.desugar(opts.vector_width),
+                        eq.rhs.desugar(opts.vector_width),
+                    )
+                };
+                (
+                    lang::VecLang::instantiate(&lhs),
+                    lang::VecLang::instantiate(&rhs),
+                )
+            })
+            .collect())
+    };
+    let pairs1 = read(&opts.rules1)?;
+    let pairs2 = read(&opts.rules2)?;
+
+    let params = ruler::DeriveParams {
+        in1: opts.rules1.clone(),
+        in2: opts.rules2.clone(),
+        out: opts.output.clone(),
+        iter_limit: opts.iter_limit,
+        node_limit: opts.node_limit,
+        time_limit: opts.time_limit,
+    };
+    let report = ruler::derive_pairs(&params, &pairs1, &pairs2);
+
+    let file = fs::File::create(&opts.output)
+        .with_context(|| format!("create {:?}", opts.output))?;
+    serde_json::to_writer_pretty(file, &report)?;
+    Ok(())
+}
+
+/// Export a ruleset so that it can be used with other rewriting tools.
+fn export(opts: ExportOpts) -> Res<()> {
+    ruler::export::<lang::VecLang>(&opts.input, &opts.output, opts.format)
+        .with_context(|| format!("export {:?}", opts.input))?;
+    Ok(())
+}
+
 fn main() -> Res<()> {
     let _ = env_logger::builder().try_init();
 
@@ -245,5 +625,9 @@ fn main() -> Res<()> {
     match args.nested {
         Commands::Synth(opts) => synth(opts),
         Commands::Compile(opts) => compile(opts),
+        Commands::Minimize(opts) => minimize(opts),
+        Commands::Derive(opts) => derive(opts),
+        Commands::Export(opts) => export(opts),
+        Commands::Merge(opts) => merge(opts),
     }
 }
@@ -3,29 +3,48 @@ use comp_gen::{
     ruler::{egg, SynthLanguage},
 };
 use log::{debug, warn};
+use rand_pcg::Pcg32;
 use z3::ast::Ast;
 
-use crate::{fuzz::FuzzEquals, lang};
+use crate::{
+    fuzz::FuzzEquals,
+    lang,
+    synthesis::{parse_guard, SmtUnknownPolicy},
+};
+
+/// The answer of the SMT solver to whether two patterns are equal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmtResult {
+    Equal,
+    /// The patterns differ, with the model that distinguishes them.
+    NotEqual(String),
+    /// The solver gave up, for example because it timed out.
+    Unknown,
+    /// One of the patterns uses an operator that we can't translate.
+    Untranslatable,
+}
 
 pub trait SmtEquals: ruler::SynthLanguage {
     fn smt_equals(
-        synth: &mut ruler::Synthesizer<Self, ruler::Init>,
         lhs: &egg::Pattern<Self>,
         rhs: &egg::Pattern<Self>,
-    ) -> bool;
+        guard: Option<&ruler::Guard>,
+        timeout_ms: u64,
+    ) -> SmtResult;
 }
 
 impl SmtEquals for lang::VecLang {
     fn smt_equals(
-        synth: &mut ruler::Synthesizer<Self, ruler::Init>,
         lhs: &egg::Pattern<Self>,
         rhs: &egg::Pattern<Self>,
-    ) -> bool {
+        guard: Option<&ruler::Guard>,
+        timeout_ms: u64,
+    ) -> SmtResult {
         // if the expressions dont have the same type, they can't be equal
         // abort now
 
         let mut cfg = z3::Config::new();
-        cfg.set_timeout_msec(10000);
+        cfg.set_timeout_msec(timeout_ms);
         let ctx = z3::Context::new(&cfg);
         let mut solver = z3::Solver::new(&ctx);
 
@@ -42,30 +61,125 @@ impl SmtEquals for lang::VecLang {
             egg_to_z3(&ctx, &mut solver, &Self::instantiate(rhs), &sqrt_fun);
 
         // if we can translate egg to z3 for both lhs, and rhs, then
-        // run the z3 solver. otherwise fallback to fuzz_equals
+        // run the z3 solver.
         if let (Some(lexpr), Some(rexpr)) = (&left, &right) {
             debug!("z3 check {} != {}", lexpr, rexpr);
 
+            // conditional rules only have to hold when the guard does
+            if let Some(guard) = guard {
+                match guard_to_z3(&ctx, guard) {
+                    Some(g) => solver.assert(&g),
+                    None => return SmtResult::Untranslatable,
+                }
+            }
+
             // check to see if lexpr is NOT equal to rexpr.
             // if we can't find a counter example to this
             // then we know that they are equal.
             solver.assert(&lexpr._eq(rexpr).not());
 
             let smt = match solver.check() {
-                z3::SatResult::Unsat => true,
-                z3::SatResult::Unknown | z3::SatResult::Sat => false,
+                z3::SatResult::Unsat => SmtResult::Equal,
+                z3::SatResult::Sat => SmtResult::NotEqual(
+                    solver
+                        .get_model()
+                        .map(|m| m.to_string())
+                        .unwrap_or_default(),
+                ),
+                z3::SatResult::Unknown => SmtResult::Unknown,
             };
 
-            debug!("z3 result: {smt}");
+            debug!("z3 result: {smt:?}");
 
             smt
         } else {
+            SmtResult::Untranslatable
+        }
+    }
+}
+
+/// Validate `lhs => rhs`, under `guard` if there is one, with the SMT
+/// solver, handling inconclusive answers according to the configured
+/// `SmtUnknownPolicy`. Unconditional rules that can't be translated to SMT
+/// are fuzzed instead. Fuzzing ignores guards, so conditional rules are only
+/// accepted if they are proven.
+pub fn validate(
+    synth: &ruler::Synthesizer<lang::VecLang, ruler::Init>,
+    rng: &mut Pcg32,
+    lhs: &egg::Pattern<lang::VecLang>,
+    rhs: &egg::Pattern<lang::VecLang>,
+    guard: Option<&ruler::Guard>,
+) -> ruler::ValidationResult {
+    let config = &synth.lang_config;
+    let mut fuzz = || {
+        (guard.is_none()
+            && lang::VecLang::fuzz_equals(synth, rng, lhs, rhs, false))
+        .then(|| ruler::Validation::Fuzz)
+    };
+
+    let res =
+        match lang::VecLang::smt_equals(lhs, rhs, guard, config.smt_timeout_ms)
+        {
+            SmtResult::Unknown => {
+                warn!("z3 couldn't decide {lhs} => {rhs}");
+                match config.smt_unknown {
+                    SmtUnknownPolicy::Reject => SmtResult::Unknown,
+                    SmtUnknownPolicy::AcceptIfFuzz => {
+                        return ruler::ValidationResult::Unknown(fuzz());
+                    }
+                    SmtUnknownPolicy::Retry { timeout_ms } => {
+                        debug!("retrying with a timeout of {timeout_ms}ms");
+                        lang::VecLang::smt_equals(lhs, rhs, guard, timeout_ms)
+                    }
+                }
+            }
+            res => res,
+        };
+
+    match res {
+        SmtResult::Equal => {
+            ruler::ValidationResult::Valid(ruler::Validation::Smt)
+        }
+        SmtResult::NotEqual(model) => ruler::ValidationResult::Invalid(
+            format!("z3 found a counterexample: {model}"),
+        ),
+        SmtResult::Unknown => ruler::ValidationResult::Unknown(None),
+        SmtResult::Untranslatable if guard.is_some() => {
+            ruler::ValidationResult::Invalid(
+                "conditional rule couldn't be translated to smt".to_string(),
+            )
+        }
+        SmtResult::Untranslatable => {
             warn!("Couldn't translate {lhs} or {rhs} to smt");
-            Self::fuzz_equals(synth, lhs, rhs, false)
+            match fuzz() {
+                Some(validation) => ruler::ValidationResult::Valid(validation),
+                None => ruler::ValidationResult::Invalid(
+                    "fuzzing found a counterexample".to_string(),
+                ),
+            }
         }
     }
 }
 
+/// Translate a guard like `?a != 0` into a z3 constraint on the variable.
+fn guard_to_z3<'a>(
+    ctx: &'a z3::Context,
+    guard: &ruler::Guard,
+) -> Option<z3::ast::Bool<'a>> {
+    let (op, n) = parse_guard(&guard.pred)?;
+    let var = z3::ast::Int::new_const(ctx, guard.var.trim_start_matches('?'));
+    let n = z3::ast::Int::from_i64(ctx, n);
+    Some(match op {
+        "!=" => var._eq(&n).not(),
+        "==" => var._eq(&n),
+        "<" => var.lt(&n),
+        "<=" => var.le(&n),
+        ">" => var.gt(&n),
+        ">=" => var.ge(&n),
+        _ => return None,
+    })
+}
+
 /// Translate an egg::RecExpr into an equivalent z3 expression.
 /// This only works for operations on integers for now.
 pub fn egg_to_z3<'a>(
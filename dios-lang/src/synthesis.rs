@@ -14,7 +14,7 @@ use ruler::{
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, path::PathBuf};
 
-use crate::{fuzz::FuzzEquals, lang, smt::SmtEquals, Res};
+use crate::{fuzz::FuzzEquals, lang, smt, Res};
 
 pub fn split_into_halves(n: usize) -> (usize, usize) {
     if n % 2 == 0 {
@@ -202,6 +202,25 @@ pub struct DiosSeedRules {
     pub rhs: String,
 }
 
+/// What to do with a rule when the SMT solver can't decide whether it's
+/// valid.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtUnknownPolicy {
+    /// Treat the rule as invalid.
+    #[default]
+    Reject,
+    /// Keep the rule if fuzzing doesn't find a counterexample.
+    AcceptIfFuzz,
+    /// Ask the solver again with a longer timeout, and reject the rule if it
+    /// still can't decide.
+    Retry { timeout_ms: u64 },
+}
+
+fn default_smt_timeout() -> u64 {
+    10_000
+}
+
 /// Dios configuration struct
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DiosConfig {
@@ -216,6 +235,21 @@ pub struct DiosConfig {
     pub vector_size: usize,
     pub always_smt: bool,
     pub smt_fallback: bool,
+    /// Timeout for each SMT query in milliseconds.
+    #[serde(default = "default_smt_timeout")]
+    pub smt_timeout_ms: u64,
+    #[serde(default)]
+    pub smt_unknown: SmtUnknownPolicy,
+    /// Rank candidates that relate scalar and vector code higher when
+    /// choosing rules.
+    #[serde(default)]
+    pub prefer_vector_ops: bool,
+    /// Predicates that conditional rules can be guarded by, of the form
+    /// `<op> <int>` where `<op>` is one of `!=`, `==`, `<`, `<=`, `>`, `>=`.
+    /// For example `!= 0` for division. Conditional rules are only inferred
+    /// when this isn't empty.
+    #[serde(default)]
+    pub guards: Vec<String>,
     pub ruler_config: ruler::SynthParams,
 }
 
@@ -233,6 +267,10 @@ impl Default for DiosConfig {
             vector_size: 1,
             always_smt: false,
             smt_fallback: true,
+            smt_timeout_ms: default_smt_timeout(),
+            smt_unknown: SmtUnknownPolicy::default(),
+            prefer_vector_ops: false,
+            guards: vec![],
             ruler_config: ruler::SynthParams::default(),
         }
     }
@@ -268,7 +306,7 @@ impl SynthLanguage for lang::VecLang {
 
     fn eval<'a, F>(&'a self, cvec_len: usize, mut get: F) -> CVec<Self>
     where
-        F: FnMut(&'a Id) -> &'a CVec<Self>,
+        F: FnMut(&'a Id) -> &'a [Option<Self::Constant>],
     {
         match self {
             lang::VecLang::Const(i) => vec![Some(i.clone()); cvec_len],
@@ -552,19 +590,16 @@ impl SynthLanguage for lang::VecLang {
 
         // read and add seed rules from config
         for rule in &synth.lang_config.seed_rules {
-            let rule: Equality<lang::VecLang> = Equality::new(
+            let mut rule: Equality<lang::VecLang> = Equality::new(
                 &rule.lhs.parse().unwrap(),
                 &rule.rhs.parse().unwrap(),
             )
             .unwrap();
-            synth
-                .equalities
-                .insert(format!("{} <=> {}", rule.lhs, rule.rhs).into(), rule);
+            rule.learned(0, 0, ruler::Validation::Seed, 0.0);
+            synth.equalities.insert(rule.key.clone(), rule);
         }
 
-        let mut egraph = egg::EGraph::new(SynthAnalysis {
-            cvec_len: cvec_size,
-        });
+        let mut egraph = egg::EGraph::new(SynthAnalysis::new(cvec_size));
 
         ////// HACK
         // lol i don't remember why this is a hack. I think I wanted this to be done
@@ -611,13 +646,25 @@ impl SynthLanguage for lang::VecLang {
 
             log::debug!("cvec for `{var}`: {cvec:?}");
 
-            egraph[id].data.cvec = cvec;
+            egraph[id].data.cvec = cvec.into();
         }
 
         // set egraph to the one we just constructed
         synth.egraph = egraph;
     }
 
+    fn domain_score(
+        synth: &Synthesizer<Self, ruler::Init>,
+        lhs: &egg::Pattern<Self>,
+        rhs: &egg::Pattern<Self>,
+    ) -> Vec<i32> {
+        if synth.lang_config.prefer_vector_ops {
+            vec![vector_score(lhs, rhs)]
+        } else {
+            vec![]
+        }
+    }
+
     fn make_layer<'a>(
         ids: Vec<Id>,
         synth: &'a Synthesizer<Self, ruler::Init>,
@@ -766,23 +813,61 @@ impl SynthLanguage for lang::VecLang {
     }
 
     fn is_valid(
-        synth: &mut Synthesizer<Self, ruler::Init>,
+        synth: &Synthesizer<Self, ruler::Init>,
+        rng: &mut Pcg32,
         lhs: &egg::Pattern<Self>,
         rhs: &egg::Pattern<Self>,
-    ) -> bool {
+    ) -> ruler::ValidationResult {
         let x = if synth.lang_config.always_smt {
-            Self::smt_equals(synth, lhs, rhs)
+            smt::validate(synth, rng, lhs, rhs, None)
         } else {
-            let fuzz = Self::fuzz_equals(synth, lhs, rhs, false);
+            let fuzz = Self::fuzz_equals(synth, rng, lhs, rhs, false);
             // if fuzz succeeds and `smt_fallback` is enabled, run `smt_equals`.
             if synth.lang_config.smt_fallback && fuzz {
                 debug!("falling back to smt");
-                Self::smt_equals(synth, lhs, rhs)
+                smt::validate(synth, rng, lhs, rhs, None)
+            } else if fuzz {
+                ruler::ValidationResult::Valid(ruler::Validation::Fuzz)
             } else {
-                false
+                ruler::ValidationResult::Invalid(
+                    "fuzzing found a counterexample".to_string(),
+                )
             }
         };
-        debug!("Checking {lhs} => {rhs}: {x}");
+        debug!("Checking {lhs} => {rhs}: {x:?}");
+        x
+    }
+
+    fn guard_predicates(synth: &Synthesizer<Self, ruler::Init>) -> Vec<String> {
+        synth.lang_config.guards.clone()
+    }
+
+    fn eval_guard(pred: &str, c: &lang::Value) -> bool {
+        match (parse_guard(pred), c) {
+            (Some((op, n)), lang::Value::Int(i)) => match op {
+                "!=" => *i != n,
+                "==" => *i == n,
+                "<" => *i < n,
+                "<=" => *i <= n,
+                ">" => *i > n,
+                ">=" => *i >= n,
+                _ => unreachable!(),
+            },
+            _ => false,
+        }
+    }
+
+    fn is_valid_guarded(
+        synth: &Synthesizer<Self, ruler::Init>,
+        rng: &mut Pcg32,
+        lhs: &egg::Pattern<Self>,
+        rhs: &egg::Pattern<Self>,
+        guard: &ruler::Guard,
+    ) -> ruler::ValidationResult {
+        // fuzzing doesn't know about guards, so conditional rules are always
+        // checked with smt
+        let x = smt::validate(synth, rng, lhs, rhs, Some(guard));
+        debug!("Checking {lhs} => {rhs} if {guard}: {x:?}");
         x
     }
 
@@ -931,6 +1016,53 @@ fn get_vars(
     })
 }
 
+/// Split a guard predicate like `!= 0` into its comparison and constant.
+pub fn parse_guard(pred: &str) -> Option<(&str, i64)> {
+    let (op, n) = pred.trim().split_once(' ')?;
+    let n = n.trim().parse().ok()?;
+    ["!=", "==", "<", "<=", ">", ">="]
+        .contains(&op)
+        .then(|| (op, n))
+}
+
+/// The number of vector operations in `pat`.
+fn vector_ops(pat: &egg::Pattern<lang::VecLang>) -> usize {
+    pat.ast
+        .as_ref()
+        .iter()
+        .filter(|n| {
+            matches!(
+                n,
+                egg::ENodeOrVar::ENode(
+                    lang::VecLang::Vec(_)
+                        | lang::VecLang::LitVec(_)
+                        | lang::VecLang::VecAdd(_)
+                        | lang::VecLang::VecMinus(_)
+                        | lang::VecLang::VecMul(_)
+                        | lang::VecLang::VecDiv(_)
+                        | lang::VecLang::VecMulSgn(_)
+                        | lang::VecLang::VecSqrtSgn(_)
+                        | lang::VecLang::VecNeg(_)
+                        | lang::VecLang::VecSqrt(_)
+                        | lang::VecLang::VecSgn(_)
+                        | lang::VecLang::VecMAC(_)
+                        | lang::VecLang::VecMULS(_)
+                )
+            )
+        })
+        .count()
+}
+
+/// How many vector operations one side of a rule has over the other, so
+/// that rules between scalar and vector code rank first. This doesn't
+/// depend on which way around the rule is written.
+fn vector_score(
+    lhs: &egg::Pattern<lang::VecLang>,
+    rhs: &egg::Pattern<lang::VecLang>,
+) -> i32 {
+    (vector_ops(rhs) as i32 - vector_ops(lhs) as i32).abs()
+}
+
 fn unique_vars(
     node: &lang::VecLang,
     egraph: &EGraph<lang::VecLang, SynthAnalysis>,
@@ -955,6 +1087,7 @@ pub fn vecs_eq(lvec: &CVec<lang::VecLang>, rvec: &CVec<lang::VecLang>) -> bool {
 pub fn run(
     dios_config: DiosConfig,
     chkpt_path: Option<PathBuf>,
+    resume: bool,
 ) -> Res<ruler::Report<lang::VecLang>> {
     log::info!("running with config: {dios_config:#?}");
 
@@ -965,8 +1098,11 @@ pub fn run(
     )
     .init();
 
+    // an explicit checkpoint takes precedence over resuming
     if let Some(chkpt) = chkpt_path {
-        syn.load_checkpoint(&chkpt);
+        syn.load_checkpoint(&chkpt)?;
+    } else if resume {
+        syn.resume()?;
     }
 
     // run the synthesizer
@@ -977,3 +1113,34 @@ pub fn run(
         report,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_guard() {
+        assert_eq!(parse_guard("!= 0"), Some(("!=", 0)));
+        assert_eq!(parse_guard("  >= -3 "), Some((">=", -3)));
+        assert_eq!(parse_guard("< 10"), Some(("<", 10)));
+        assert_eq!(parse_guard("=< 0"), None);
+        assert_eq!(parse_guard("!= x"), None);
+        assert_eq!(parse_guard("!=0"), None);
+        assert_eq!(parse_guard(""), None);
+    }
+
+    #[test]
+    fn test_vector_score_is_symmetric() {
+        let scalar: egg::Pattern<lang::VecLang> =
+            "(Vec (+ ?a ?b))".parse().unwrap();
+        let vector: egg::Pattern<lang::VecLang> =
+            "(VecAdd (Vec ?a) (Vec ?b))".parse().unwrap();
+        assert_eq!(vector_score(&scalar, &vector), 2);
+        assert_eq!(vector_score(&vector, &scalar), 2);
+
+        // both rules rank above a rule that only touches scalar code
+        let l: egg::Pattern<lang::VecLang> = "(+ ?a ?b)".parse().unwrap();
+        let r: egg::Pattern<lang::VecLang> = "(+ ?b ?a)".parse().unwrap();
+        assert_eq!(vector_score(&l, &r), 0);
+    }
+}
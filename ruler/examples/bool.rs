@@ -0,0 +1,125 @@
+//! Synthesize rewrite rules for boolean logic with the command line of
+//! [ruler::Main], for example
+//!
+//! ```text
+//! cargo run --example bool --features cli -- synth --iters 2 --variables 3
+//! ```
+use itertools::Itertools;
+use rand_pcg::Pcg32;
+use ruler::{
+    egg::{self, define_language, Id, Pattern, Var},
+    map, self_product, CVec, HashMap, SynthAnalysis, SynthLanguage,
+    Synthesizer, Validation, ValidationResult,
+};
+use serde::{Deserialize, Serialize};
+
+define_language! {
+    #[derive(Serialize, Deserialize)]
+    pub enum Bool {
+        "~" = Not([Id; 1]),
+        "&" = And([Id; 2]),
+        "|" = Or([Id; 2]),
+        "^" = Xor([Id; 2]),
+        Lit(bool),
+        Var(egg::Symbol),
+    }
+}
+
+impl SynthLanguage for Bool {
+    type Constant = bool;
+    type Config = ();
+
+    fn eval<'a, F>(&'a self, cvec_len: usize, mut get: F) -> CVec<Self>
+    where
+        F: FnMut(&'a Id) -> &'a [Option<Self::Constant>],
+    {
+        match self {
+            Bool::Not([a]) => map!(get, a => Some(!a)),
+            Bool::And([a, b]) => map!(get, a, b => Some(*a & *b)),
+            Bool::Or([a, b]) => map!(get, a, b => Some(*a | *b)),
+            Bool::Xor([a, b]) => map!(get, a, b => Some(*a ^ *b)),
+            Bool::Lit(b) => vec![Some(*b); cvec_len],
+            Bool::Var(_) => vec![],
+        }
+    }
+
+    fn to_var(&self) -> Option<egg::Symbol> {
+        match self {
+            Bool::Var(sym) => Some(*sym),
+            _ => None,
+        }
+    }
+
+    fn mk_var(sym: egg::Symbol) -> Self {
+        Bool::Var(sym)
+    }
+
+    fn to_constant(&self) -> Option<&Self::Constant> {
+        match self {
+            Bool::Lit(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn mk_constant(c: Self::Constant) -> Option<Self> {
+        Some(Bool::Lit(c))
+    }
+
+    fn init_synth(synth: &mut Synthesizer<Self, ruler::Uninit>) {
+        // the cvecs of the variables enumerate every assignment
+        let cvecs =
+            self_product(&[Some(false), Some(true)], synth.params.variables);
+        let cvec_len = cvecs.first().map_or(1, |cvec| cvec.len());
+
+        let mut egraph = egg::EGraph::new(SynthAnalysis::new(cvec_len));
+        egraph.add(Bool::Lit(false));
+        egraph.add(Bool::Lit(true));
+        for (i, cvec) in cvecs.into_iter().enumerate() {
+            let id = egraph.add(Bool::Var(ruler::letter(i).into()));
+            egraph[id].data.cvec = cvec.into();
+        }
+        synth.egraph = egraph;
+    }
+
+    fn make_layer<'a>(
+        ids: Vec<Id>,
+        _synth: &'a Synthesizer<Self, ruler::Init>,
+        _iter: usize,
+    ) -> Box<dyn Iterator<Item = Self> + 'a> {
+        let unops = ids.clone().into_iter().map(|a| Bool::Not([a]));
+        let binops = (0..2)
+            .map(move |_| ids.clone())
+            .multi_cartesian_product()
+            .flat_map(|ids| {
+                let x = [ids[0], ids[1]];
+                vec![Bool::And(x), Bool::Or(x), Bool::Xor(x)]
+            });
+        Box::new(unops.chain(binops))
+    }
+
+    fn is_valid(
+        _synth: &Synthesizer<Self, ruler::Init>,
+        _rng: &mut Pcg32,
+        lhs: &Pattern<Self>,
+        rhs: &Pattern<Self>,
+    ) -> ValidationResult {
+        // check the rule on every assignment of its variables
+        let vars = lhs.vars().into_iter().chain(rhs.vars()).unique();
+        let vars: Vec<Var> = vars.collect();
+        let cvecs = self_product(&[Some(false), Some(true)], vars.len());
+        let cvec_len = cvecs.first().map_or(1, |cvec| cvec.len());
+        let ctx: HashMap<Var, CVec<Self>> =
+            vars.into_iter().zip(cvecs).collect();
+        let l = Self::eval_pattern(lhs, &ctx, cvec_len);
+        let r = Self::eval_pattern(rhs, &ctx, cvec_len);
+        if l == r {
+            ValidationResult::Valid(Validation::Fuzz)
+        } else {
+            ValidationResult::Invalid(format!("{l:?} != {r:?}"))
+        }
+    }
+}
+
+fn main() {
+    <Bool as ruler::Main>::main()
+}
@@ -0,0 +1,371 @@
+//! Saving and restoring the state of a [Synthesizer].
+//!
+//! A checkpoint file is two CBOR values written back to back: a
+//! [CheckpointHeader] that identifies the format and records the
+//! [SynthParams] of the run, followed by the synthesizer itself.
+use std::{
+    convert::TryFrom,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Init, SynthLanguage, SynthParams, Synthesizer};
+
+/// Identifies ruler checkpoint files.
+const CHECKPOINT_MAGIC: &str = "ruler-checkpoint";
+
+/// Bump this whenever the serialized layout of [Synthesizer] changes.
+///  - 2: checkpoints start with a [CheckpointHeader]
+///  - 3: equalities record their provenance
+///  - 4: rules with unknown validity are kept
+///  - 5: rejected rules are kept
+///  - 6: equalities can have a guard
+///  - 7: e-class cvecs are shared slices
+///  - 8: equalities are keyed by their [RuleKey](crate::RuleKey)
+pub const CHECKPOINT_VERSION: u32 = 8;
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointHeader {
+    magic: String,
+    version: u32,
+    params: SynthParams,
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    /// The file isn't a checkpoint, or is from a version of ruler that
+    /// didn't write a header.
+    NotACheckpoint(PathBuf),
+    /// The checkpoint was written with a different format version.
+    Incompatible {
+        found: u32,
+        expected: u32,
+    },
+    /// The header is truncated or malformed.
+    CorruptHeader(String),
+    Deserialize(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "io error: {e}"),
+            CheckpointError::NotACheckpoint(path) => {
+                write!(f, "{path:?} is not a ruler checkpoint")
+            }
+            CheckpointError::Incompatible { found, expected } => write!(
+                f,
+                "checkpoint has format version {found}, but this version of ruler expects {expected}"
+            ),
+            CheckpointError::CorruptHeader(e) => {
+                write!(f, "checkpoint header is corrupt: {e}")
+            }
+            CheckpointError::Deserialize(e) => {
+                write!(f, "failed to deserialize checkpoint: {e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+/// The name of the checkpoint taken after inner iteration `inner` of outer
+/// iteration `outer`.
+fn checkpoint_name(outer: usize, inner: usize) -> String {
+    format!("ruler_out{outer}_in{inner}.chkpt")
+}
+
+/// Parse the `(outer, inner)` iteration out of a checkpoint file name.
+fn parse_checkpoint_name(name: &str) -> Option<(usize, usize)> {
+    let rest = name.strip_prefix("ruler_out")?.strip_suffix(".chkpt")?;
+    let (outer, inner) = rest.split_once("_in")?;
+    Some((outer.parse().ok()?, inner.parse().ok()?))
+}
+
+/// All the checkpoints in `dir`, oldest first.
+pub fn list_checkpoints(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut chkpts = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(iter) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(parse_checkpoint_name)
+        {
+            chkpts.push((iter, path));
+        }
+    }
+    chkpts.sort();
+    Ok(chkpts.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The most recent checkpoint in `dir`, if there is one.
+pub fn latest_checkpoint(dir: &Path) -> io::Result<Option<PathBuf>> {
+    Ok(list_checkpoints(dir)?.pop())
+}
+
+/// Read the header at the start of the checkpoint `path`. The magic and
+/// the version are checked before the rest of the header is deserialized,
+/// so that checkpoints of other versions are reported as incompatible even
+/// if their params changed.
+fn read_header<R: io::Read>(
+    reader: R,
+    path: &Path,
+) -> Result<CheckpointHeader, CheckpointError> {
+    use ciborium::value::Value;
+
+    let value: Value = ciborium::de::from_reader(reader)
+        .map_err(|e| CheckpointError::CorruptHeader(format!("{e:?}")))?;
+    let field = |name: &str| {
+        value
+            .as_map()?
+            .iter()
+            .find(|(key, _)| key.as_text() == Some(name))
+            .map(|(_, v)| v)
+    };
+
+    if field("magic").and_then(Value::as_text) != Some(CHECKPOINT_MAGIC) {
+        return Err(CheckpointError::NotACheckpoint(path.to_path_buf()));
+    }
+    let version = field("version")
+        .and_then(Value::as_integer)
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or_else(|| {
+            CheckpointError::CorruptHeader("missing version".to_string())
+        })?;
+    if version != CHECKPOINT_VERSION {
+        return Err(CheckpointError::Incompatible {
+            found: version,
+            expected: CHECKPOINT_VERSION,
+        });
+    }
+    value
+        .deserialized()
+        .map_err(|e| CheckpointError::CorruptHeader(format!("{e:?}")))
+}
+
+/// Log a warning for every parameter that is different in `new` than it was
+/// in `old`.
+fn warn_changed_params(old: &SynthParams, new: &SynthParams) {
+    let (old, new) =
+        match (serde_json::to_value(old), serde_json::to_value(new)) {
+            (
+                Ok(serde_json::Value::Object(old)),
+                Ok(serde_json::Value::Object(new)),
+            ) => (old, new),
+            _ => return,
+        };
+    for (key, new_val) in &new {
+        let old_val = old.get(key).unwrap_or(&serde_json::Value::Null);
+        if old_val != new_val {
+            log::warn!(
+                "Parameter `{key}` changed since the checkpoint: {old_val} -> {new_val}"
+            );
+        }
+    }
+}
+
+impl<L: SynthLanguage> Synthesizer<L, Init> {
+    /// The directory that checkpoints are written to and resumed from.
+    fn checkpoint_dir(&self) -> PathBuf {
+        self.params
+            .checkpoint_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    pub(crate) fn save_checkpoint(&self) {
+        if self.params.enable_checkpointing {
+            let start = Instant::now();
+            log::info!("Starting a checkpoint!");
+
+            match self.write_checkpoint() {
+                Ok(path) => {
+                    log::info!(
+                        "Finished writing checkpoint {path:?} in {:?}",
+                        start.elapsed()
+                    );
+                    self.event(crate::Event::Checkpoint {
+                        path,
+                        seconds: start.elapsed().as_secs_f64(),
+                    });
+                }
+                Err(e) => log::warn!("Failed to write checkpoint: {e}"),
+            }
+
+            if let Err(e) = self.prune_checkpoints() {
+                log::warn!("Failed to remove old checkpoints: {e}");
+            }
+        } else {
+            log::info!("Skipping checkpoint!");
+        }
+    }
+
+    /// Write the checkpoint to a temporary file and then move it into
+    /// place, so that an interrupted write never leaves behind a truncated
+    /// checkpoint.
+    fn write_checkpoint(&self) -> Result<PathBuf, CheckpointError> {
+        let dir = self.checkpoint_dir();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(checkpoint_name(self.outer_iter, self.inner_iter));
+        let tmp = path.with_extension("chkpt.tmp");
+
+        let mut file = io::BufWriter::new(fs::File::create(&tmp)?);
+        let header = CheckpointHeader {
+            magic: CHECKPOINT_MAGIC.to_string(),
+            version: CHECKPOINT_VERSION,
+            params: self.params.clone(),
+        };
+        ciborium::ser::into_writer(&header, &mut file)
+            .and_then(|_| ciborium::ser::into_writer(&self, &mut file))
+            .map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, format!("{e:?}"))
+            })?;
+        io::Write::flush(&mut file)?;
+        drop(file);
+
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+
+    /// Only keep the newest `checkpoint_keep` checkpoints.
+    fn prune_checkpoints(&self) -> io::Result<()> {
+        if let Some(keep) = self.params.checkpoint_keep {
+            let chkpts = list_checkpoints(&self.checkpoint_dir())?;
+            let remove = chkpts.len().saturating_sub(keep.max(1));
+            for path in &chkpts[..remove] {
+                log::info!("Removing old checkpoint {path:?}");
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore the synthesizer from the checkpoint at `filename`. The
+    /// current params are kept, and any that differ from the params of the
+    /// checkpointed run are reported.
+    pub fn load_checkpoint(
+        &mut self,
+        filename: &Path,
+    ) -> Result<(), CheckpointError> {
+        let start = Instant::now();
+        log::info!("Loading checkpoint: {filename:?}");
+        let mut chkpt_file = io::BufReader::new(fs::File::open(filename)?);
+
+        let header = read_header(&mut chkpt_file, filename)?;
+        warn_changed_params(&header.params, &self.params);
+
+        let copy: Self = ciborium::de::from_reader(&mut chkpt_file)
+            .map_err(|e| CheckpointError::Deserialize(format!("{e:?}")))?;
+        // copy everything besides the params
+        *self = Self {
+            params: self.params.clone(),
+            ..copy
+        };
+        // we save the index of the finished iter, so this is off by one.
+        // incrementing it corrects it
+        self.inner_iter += 1;
+
+        log::info!("Loaded in {:?}", start.elapsed());
+
+        // serialized egraphs don't necessarily maintain the correct
+        // invariants. reubild them so that they are correct
+        log::info!("Rebuilding egraphs...");
+        self.egraph.rebuild();
+        self.initial_egraph.rebuild();
+        log::info!("Done");
+
+        self.inner_restored = false;
+        Ok(())
+    }
+
+    /// Restore the synthesizer from the newest checkpoint in the checkpoint
+    /// directory. Returns the checkpoint that was loaded, or `None` if there
+    /// aren't any checkpoints.
+    pub fn resume(&mut self) -> Result<Option<PathBuf>, CheckpointError> {
+        let dir = self.checkpoint_dir();
+        let latest = if dir.exists() {
+            latest_checkpoint(&dir)?
+        } else {
+            None
+        };
+        match latest {
+            Some(path) => {
+                self.load_checkpoint(&path)?;
+                Ok(Some(path))
+            }
+            None => {
+                log::info!("No checkpoint found in {dir:?}, starting fresh");
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(magic: &str, version: u32) -> Vec<u8> {
+        let header = CheckpointHeader {
+            magic: magic.to_string(),
+            version,
+            params: SynthParams::default(),
+        };
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&header, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_checkpoint_names() {
+        let name = checkpoint_name(3, 12);
+        assert_eq!(name, "ruler_out3_in12.chkpt");
+        assert_eq!(parse_checkpoint_name(&name), Some((3, 12)));
+        assert_eq!(parse_checkpoint_name("ruler_out3_in12.chkpt.tmp"), None);
+        assert_eq!(parse_checkpoint_name("ruler_outx_in1.chkpt"), None);
+        assert_eq!(parse_checkpoint_name("out.json"), None);
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let bytes = header_bytes(CHECKPOINT_MAGIC, CHECKPOINT_VERSION);
+        let header = read_header(&bytes[..], Path::new("c")).unwrap();
+        assert_eq!(header.magic, CHECKPOINT_MAGIC);
+        assert_eq!(header.version, CHECKPOINT_VERSION);
+        assert_eq!(header.params.iters, SynthParams::default().iters);
+    }
+
+    #[test]
+    fn test_header_errors() {
+        let path = Path::new("c");
+        let bytes = header_bytes("something-else", CHECKPOINT_VERSION);
+        assert!(matches!(
+            read_header(&bytes[..], path),
+            Err(CheckpointError::NotACheckpoint(_))
+        ));
+
+        let bytes = header_bytes(CHECKPOINT_MAGIC, CHECKPOINT_VERSION - 1);
+        assert!(matches!(
+            read_header(&bytes[..], path),
+            Err(CheckpointError::Incompatible { found, .. })
+                if found == CHECKPOINT_VERSION - 1
+        ));
+
+        let bytes = header_bytes(CHECKPOINT_MAGIC, CHECKPOINT_VERSION);
+        assert!(matches!(
+            read_header(&bytes[..bytes.len() / 2], path),
+            Err(CheckpointError::CorruptHeader(_))
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+//! The command line interface of [Main](crate::Main), enabled by the `cli`
+//! feature. A language gets a rule synthesis binary with
+//!
+//! ```ignore
+//! fn main() {
+//!     <MyLang as ruler::Main>::main()
+//! }
+//! ```
+use argh::FromArgs;
+
+use crate::*;
+
+#[derive(FromArgs)]
+/// Synthesize rewrite rules and work with rulesets.
+struct Cli {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    /// synthesize a ruleset
+    Synth(SynthOpts),
+    /// check the derivability of two rulesets against each other
+    Derive(DeriveOpts),
+    /// convert rewrites from a CVC4 log to a ruleset
+    ConvertSexp(ConvertOpts),
+    /// remove the rules of a ruleset that can be derived from the others
+    Minimize(MinimizeOpts),
+}
+
+impl Command {
+    /// Parse the command from the arguments of the process.
+    pub fn cli() -> Self {
+        argh::from_env::<Cli>().command
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "synth")]
+/// Synth options.
+pub struct SynthOpts {
+    /// path to a json file with the synthesis parameters
+    #[argh(option, from_str_fn(read_params))]
+    config: Option<SynthParams>,
+
+    /// where to write the ruleset, instead of the outfile of the config
+    #[argh(option)]
+    outfile: Option<String>,
+
+    /// number of iterations
+    #[argh(option)]
+    iters: Option<usize>,
+
+    /// number of variables
+    #[argh(option)]
+    variables: Option<usize>,
+
+    /// seed for the random number generator
+    #[argh(option)]
+    seed: Option<u64>,
+
+    /// timeout in seconds for the whole run, 0 for no timeout
+    #[argh(option)]
+    abs_timeout: Option<usize>,
+
+    /// ruleset from an earlier run to start from (can be repeated)
+    #[argh(option)]
+    prior: Vec<PathBuf>,
+
+    /// write checkpoints to this directory
+    #[argh(option)]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// checkpoint to restore before synthesizing
+    #[argh(option)]
+    pub checkpoint: Option<PathBuf>,
+
+    /// resume from the newest checkpoint in the checkpoint directory
+    #[argh(switch)]
+    pub resume: bool,
+}
+
+/// Read [SynthParams] from a path (represented as a `&str`).
+fn read_params(path: &str) -> Result<SynthParams, String> {
+    SynthParams::from_path(&PathBuf::from(path))
+        .map_err(|e| format!("failed to read {path:?}: {e}"))
+}
+
+impl From<SynthOpts> for SynthParams {
+    fn from(opts: SynthOpts) -> Self {
+        let mut params = opts.config.unwrap_or_default();
+        if let Some(outfile) = opts.outfile {
+            params.outfile = outfile;
+        }
+        if let Some(iters) = opts.iters {
+            params.iters = iters;
+        }
+        if let Some(variables) = opts.variables {
+            params.variables = variables;
+        }
+        if let Some(seed) = opts.seed {
+            params.seed = seed;
+        }
+        if let Some(abs_timeout) = opts.abs_timeout {
+            params.abs_timeout = abs_timeout;
+        }
+        params.prior_rulesets.extend(opts.prior);
+        if let Some(dir) = opts.checkpoint_dir {
+            params.checkpoint_dir = Some(dir);
+            params.enable_checkpointing = true;
+        }
+        params
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "derive")]
+/// Derive options.
+pub struct DeriveOpts {
+    /// first ruleset
+    #[argh(positional)]
+    in1: String,
+
+    /// second ruleset
+    #[argh(positional)]
+    in2: String,
+
+    /// where to write the derivability report
+    #[argh(option)]
+    out: String,
+
+    /// iteration limit for deriving a rule
+    #[argh(option, default = "3")]
+    iter_limit: usize,
+
+    /// node limit for deriving a rule
+    #[argh(option, default = "100_000")]
+    node_limit: usize,
+
+    /// time limit in seconds for deriving a rule
+    #[argh(option, default = "10")]
+    time_limit: u64,
+}
+
+impl From<DeriveOpts> for DeriveParams {
+    fn from(opts: DeriveOpts) -> Self {
+        DeriveParams {
+            in1: opts.in1,
+            in2: opts.in2,
+            out: opts.out,
+            iter_limit: opts.iter_limit,
+            node_limit: opts.node_limit,
+            time_limit: opts.time_limit,
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "convert-sexp")]
+/// Convert options.
+pub struct ConvertOpts {
+    /// log of the rewrites found by CVC4
+    #[argh(positional)]
+    cvc_log: String,
+
+    /// where to write the ruleset
+    #[argh(option)]
+    out: String,
+}
+
+impl From<ConvertOpts> for ConvertParams {
+    fn from(opts: ConvertOpts) -> Self {
+        ConvertParams {
+            cvc_log: opts.cvc_log,
+            out: opts.out,
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "minimize")]
+/// Minimize options.
+pub struct MinimizeOpts {
+    /// ruleset to minimize
+    #[argh(positional)]
+    input: String,
+
+    /// where to write the minimized ruleset
+    #[argh(option)]
+    out: String,
+
+    /// where to write the report of removed rules
+    #[argh(option, default = "String::from(\"minimize_report.json\")")]
+    report: String,
+
+    /// iteration limit for deriving a rule
+    #[argh(option, default = "3")]
+    iter_limit: usize,
+
+    /// node limit for deriving a rule
+    #[argh(option, default = "100_000")]
+    node_limit: usize,
+
+    /// time limit in seconds for deriving a rule
+    #[argh(option, default = "10")]
+    time_limit: u64,
+}
+
+impl From<MinimizeOpts> for MinimizeParams {
+    fn from(opts: MinimizeOpts) -> Self {
+        MinimizeParams {
+            input: opts.input,
+            out: opts.out,
+            report: opts.report,
+            iter_limit: opts.iter_limit,
+            node_limit: opts.node_limit,
+            time_limit: opts.time_limit,
+        }
+    }
+}
@@ -3,18 +3,27 @@ use rayon::prelude::*;
 use std::fs::File;
 use std::sync::Mutex;
 
-#[allow(dead_code)]
-type Pair<L> = (RecExpr<L>, RecExpr<L>);
+pub type Pair<L> = (RecExpr<L>, RecExpr<L>);
 
-#[allow(dead_code)]
+/// Parameters for checking the derivability of two rulesets against each
+/// other.
 pub struct DeriveParams {
     pub in1: String,
     pub in2: String,
     pub out: String,
+    /// Limits for the equality saturation used to derive each rule.
     pub iter_limit: usize,
+    pub node_limit: usize,
+    pub time_limit: u64,
+}
+
+/// A rule that could be derived, along with the number of rewrites in the
+/// proof that was found for it.
+pub struct Derivation<L: SynthLanguage> {
+    pub pair: Pair<L>,
+    pub proof_length: usize,
 }
 
-#[allow(dead_code)]
 pub fn parse<L: SynthLanguage>(filename: &String) -> Vec<Pair<L>> {
     let file = File::open(filename)
         .unwrap_or_else(|_| panic!("Failed to open {}", filename));
@@ -34,93 +43,160 @@ pub fn parse<L: SynthLanguage>(filename: &String) -> Vec<Pair<L>> {
 }
 
 /// Perform derivability test between two rulesets.
-#[allow(dead_code)]
 pub fn derive<L: SynthLanguage>(params: DeriveParams) {
     let pairs1 = parse::<L>(&params.in1);
     let pairs2 = parse::<L>(&params.in2);
 
-    println!("Using {} to derive {}", params.in1, params.in2);
-    let (derivable, not_derivable) = one_way(&params, &pairs1, &pairs2);
+    let json = derive_pairs(&params, &pairs1, &pairs2);
+
+    let file = File::create(&params.out)
+        .unwrap_or_else(|_| panic!("Failed to create '{}'", &params.out));
+    serde_json::to_writer_pretty(file, &json).unwrap();
+}
+
+/// Perform derivability test between two rulesets that have already been
+/// loaded, and return the report. This is useful for languages that need to
+/// preprocess the rules before comparing them.
+pub fn derive_pairs<L: SynthLanguage>(
+    params: &DeriveParams,
+    pairs1: &[Pair<L>],
+    pairs2: &[Pair<L>],
+) -> serde_json::Value {
+    log::info!("Using {} to derive {}", params.in1, params.in2);
+    let (derivable, not_derivable) = one_way(params, pairs1, pairs2);
 
-    println!("\nUsing {} to derive {}", params.in2, params.in1);
-    let (rev_derivable, rev_not_derivable) = one_way(&params, &pairs2, &pairs1);
+    log::info!("Using {} to derive {}", params.in2, params.in1);
+    let (rev_derivable, rev_not_derivable) = one_way(params, pairs2, pairs1);
 
-    let json = serde_json::json!({
+    serde_json::json!({
         "files": [params.in1, params.in2],
         "forward": {
-            "derivable": pairs_to_eqs(&derivable),
+            "derivable": derivations_to_json(&derivable),
             "not_derivable": pairs_to_eqs(&not_derivable),
+            "not_rules": not_rules(pairs2),
         },
         "reverse": {
-            "derivable": pairs_to_eqs(&rev_derivable),
+            "derivable": derivations_to_json(&rev_derivable),
             "not_derivable": pairs_to_eqs(&rev_not_derivable),
+            "not_rules": not_rules(pairs1),
         },
-    });
+    })
+}
 
-    let file = File::create(&params.out)
-        .unwrap_or_else(|_| panic!("Failed to create '{}'", &params.out));
-    serde_json::to_writer_pretty(file, &json).unwrap();
+/// Whether a pair can be turned into an [Equality]. Pairs that can't, for
+/// example because neither side binds all of the variables of the other,
+/// are left out of the derivability check and reported separately.
+fn is_rule<L: SynthLanguage>((l, r): &Pair<L>) -> bool {
+    Equality::new(l, r).is_some()
+}
+
+fn not_rules<L: SynthLanguage>(pairs: &[Pair<L>]) -> Vec<String> {
+    pairs
+        .iter()
+        .filter(|pair| !is_rule(pair))
+        .map(|(l, r)| format!("{} = {}", l, r))
+        .collect()
 }
 
 /// Check the derivability of rules in test using the rules in src
-#[allow(dead_code)]
 fn one_way<L: SynthLanguage>(
     params: &DeriveParams,
     src: &[Pair<L>],
     test: &[Pair<L>],
-) -> (Vec<Pair<L>>, Vec<Pair<L>>) {
+) -> (Vec<Derivation<L>>, Vec<Pair<L>>) {
     let eqs: Vec<Equality<L>> =
         src.iter().flat_map(|(l, r)| Equality::new(l, r)).collect();
 
+    let (test, skipped): (Vec<&Pair<L>>, Vec<&Pair<L>>) =
+        test.iter().partition(|pair| is_rule(pair));
+    for (l, r) in &skipped {
+        log::warn!("Skipping {} = {}, it isn't a valid rule", l, r);
+    }
+
     let results = Mutex::new((vec![], vec![]));
-    let test = test.to_vec();
-    test.into_par_iter().for_each(|(l, r)| {
-        let runner = Runner::default()
-            .with_expr(&l)
-            .with_expr(&r)
-            .with_iter_limit(params.iter_limit)
-            .with_node_limit(100_000)
-            .with_time_limit(Duration::from_secs(10))
-            .with_scheduler(egg::SimpleScheduler)
-            .with_hook(|r| {
-                if r.egraph.find(r.roots[0]) == r.egraph.find(r.roots[1]) {
-                    Err(format!("Done"))
-                } else {
-                    Ok(())
-                }
-            })
-            .run(eqs.iter().flat_map(|eq| &eq.rewrites));
-
-        let l_id = runner.egraph.find(runner.roots[0]);
-        let r_id = runner.egraph.find(runner.roots[1]);
+    test.par_iter().for_each(|(l, r)| {
+        let derivation = derivation(params, &eqs, l, r);
 
         let mut results = results.lock().unwrap();
-
-        eprint!(
-            "\r{} rules are derivable, {} are not.",
+        match derivation {
+            Some(proof_length) => results.0.push(Derivation {
+                pair: (l.clone(), r.clone()),
+                proof_length,
+            }),
+            None => results.1.push((l.clone(), r.clone())),
+        }
+        log::debug!(
+            "{} rules are derivable, {} are not.",
             results.0.len(),
             results.1.len(),
         );
-
-        if l_id == r_id {
-            results.0.push((l.clone(), r.clone()));
-        } else {
-            results.1.push((l.clone(), r.clone()));
-        }
     });
 
     let results = results.into_inner().unwrap();
-    eprintln!(
-        "\r{} rules are derivable, {} are not.",
+    log::info!(
+        "{} rules are derivable, {} are not, {} aren't valid rules.",
         results.0.len(),
         results.1.len(),
+        skipped.len(),
     );
     results
 }
 
+/// Try to prove `l = r` using `eqs`. If we can, return the number of
+/// rewrites in the proof.
+fn derivation<L: SynthLanguage>(
+    params: &DeriveParams,
+    eqs: &[Equality<L>],
+    l: &RecExpr<L>,
+    r: &RecExpr<L>,
+) -> Option<usize> {
+    let mut runner: Runner<L, SynthAnalysis, ()> = Runner::default()
+        .with_explanations_enabled()
+        .with_expr(l)
+        .with_expr(r)
+        .with_iter_limit(params.iter_limit)
+        .with_node_limit(params.node_limit)
+        .with_time_limit(Duration::from_secs(params.time_limit))
+        .with_scheduler(egg::SimpleScheduler)
+        .with_hook(|r| {
+            if r.egraph.find(r.roots[0]) == r.egraph.find(r.roots[1]) {
+                Err("Done".to_string())
+            } else {
+                Ok(())
+            }
+        })
+        .run(eqs.iter().flat_map(|eq| &eq.rewrites));
+
+    if runner.egraph.find(runner.roots[0])
+        != runner.egraph.find(runner.roots[1])
+    {
+        return None;
+    }
+
+    // the flat explanation lists every term along the proof, starting
+    // with `l`, so there is one fewer rewrite than there are terms.
+    let mut explanation = runner.explain_equivalence(l, r);
+    Some(explanation.make_flat_explanation().len().saturating_sub(1))
+}
+
+fn derivations_to_json<L: SynthLanguage>(
+    derivations: &[Derivation<L>],
+) -> Vec<serde_json::Value> {
+    derivations
+        .iter()
+        .flat_map(|d| {
+            let eq = Equality::new(&d.pair.0, &d.pair.1)?;
+            Some(serde_json::json!({
+                "rule": eq,
+                "proof_length": d.proof_length,
+            }))
+        })
+        .collect()
+}
+
 fn pairs_to_eqs<L: SynthLanguage>(pairs: &[Pair<L>]) -> Vec<Equality<L>> {
     pairs
         .iter()
-        .map(|(l, r)| Equality::new(l, r).unwrap())
+        .flat_map(|(l, r)| Equality::new(l, r))
         .collect()
 }
@@ -1,8 +1,8 @@
 use crate::*;
-use egg::{Applier, CostFunction, Subst};
+use egg::{Applier, ConditionalApplier, CostFunction, Subst};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{marker::PhantomData, sync::Arc};
 
 /// Definition of an equality.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,11 +10,173 @@ use std::sync::Arc;
 #[serde(into = "SerializedEq")]
 #[serde(bound = "L: SynthLanguage")]
 pub struct Equality<L: SynthLanguage + 'static> {
+    /// A readable name for the equality, only meant for display.
     pub name: Arc<str>,
+    /// Identifies the equality up to renaming and orientation.
+    pub key: RuleKey,
     pub lhs: Pattern<L>,
     pub ids: Option<(Id, Id)>,
     pub rhs: Pattern<L>,
     pub rewrites: Vec<Rewrite<L, SynthAnalysis>>,
+    /// Where this equality came from, if it was learned by ruler.
+    pub provenance: Option<Provenance>,
+    /// The condition under which the equality holds, for conditional rules.
+    pub guard: Option<Guard>,
+}
+
+/// A canonical form of an equality that doesn't depend on how its variables
+/// are named or which side is written first. Equalities are deduplicated,
+/// rejected and checkpointed by their key.
+///
+/// Both orientations are written with the variables numbered in order of
+/// first appearance, `(+ ?0 ?1) = (+ ?1 ?0)`, and the smaller of the two is
+/// the key. The nodes are written with their [Display](std::fmt::Display)
+/// impl rather than as s-expressions, so constants are never quoted.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct RuleKey(Arc<str>);
+
+impl RuleKey {
+    pub fn new<L: Language + Display>(
+        lhs: &Pattern<L>,
+        rhs: &Pattern<L>,
+        guard: Option<&Guard>,
+    ) -> Self {
+        let forward = Self::oriented(lhs, rhs, guard);
+        let back = Self::oriented(rhs, lhs, guard);
+        RuleKey(forward.min(back).into())
+    }
+
+    fn oriented<L: Language + Display>(
+        lhs: &Pattern<L>,
+        rhs: &Pattern<L>,
+        guard: Option<&Guard>,
+    ) -> String {
+        let mut vars = HashMap::default();
+        let mut key = String::new();
+        write_canonical(&lhs.ast, root(&lhs.ast), &mut vars, &mut key);
+        key.push_str(" = ");
+        write_canonical(&rhs.ast, root(&rhs.ast), &mut vars, &mut key);
+        if let Some(guard) = guard {
+            let var = guard.var.parse::<Var>().ok();
+            match var.and_then(|v| vars.get(&v)) {
+                Some(i) => key.push_str(&format!(" if ?{} {}", i, guard.pred)),
+                None => key.push_str(&format!(" if {}", guard)),
+            }
+        }
+        key
+    }
+}
+
+impl Display for RuleKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn root<L: Language>(ast: &PatternAst<L>) -> Id {
+    Id::from(ast.as_ref().len() - 1)
+}
+
+/// Write the term at `id` as an s-expression, numbering the variables in the
+/// order they are first seen.
+fn write_canonical<L: Language + Display>(
+    ast: &PatternAst<L>,
+    id: Id,
+    vars: &mut HashMap<Var, usize>,
+    out: &mut String,
+) {
+    match &ast[id] {
+        ENodeOrVar::Var(v) => {
+            let next = vars.len();
+            let i = *vars.entry(*v).or_insert(next);
+            out.push_str(&format!("?{}", i));
+        }
+        ENodeOrVar::ENode(node) if node.is_leaf() => {
+            out.push_str(&node.to_string())
+        }
+        ENodeOrVar::ENode(node) => {
+            out.push('(');
+            out.push_str(&node.to_string());
+            for child in node.children() {
+                out.push(' ');
+                write_canonical(ast, *child, vars, out);
+            }
+            out.push(')');
+        }
+    }
+}
+
+/// A predicate on one variable of a conditional rule, for example `?a != 0`.
+/// The predicate is a string from [SynthLanguage::guard_predicates] and is
+/// interpreted by the language.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Guard {
+    pub var: String,
+    pub pred: String,
+}
+
+impl Display for Guard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.var, self.pred)
+    }
+}
+
+/// Whether `pred` holds for every defined value of `cvec`. There has to be at
+/// least one defined value.
+pub(crate) fn guard_holds<L: SynthLanguage>(
+    pred: &str,
+    cvec: &[Option<L::Constant>],
+) -> bool {
+    let mut any = false;
+    for c in cvec.iter().flatten() {
+        if !L::eval_guard(pred, c) {
+            return false;
+        }
+        any = true;
+    }
+    any
+}
+
+/// How a rule was accepted as valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Validation {
+    Fuzz,
+    Smt,
+    /// Seed rules provided by the language, which are trusted.
+    Seed,
+    /// Rules that were accepted without being validated.
+    Unvalidated,
+}
+
+/// The outcome of validating a candidate rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationResult {
+    /// The rule was accepted by the given method.
+    Valid(Validation),
+    /// The rule is invalid. Holds a counterexample or other reason.
+    Invalid(String),
+    /// The validator couldn't decide whether the rule is valid, for example
+    /// because the SMT solver timed out. If a fallback method accepted the
+    /// rule anyway, it is kept.
+    Unknown(Option<Validation>),
+}
+
+/// Metadata about when and how an equality was learned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    pub outer_iter: usize,
+    pub inner_iter: usize,
+    /// The method that accepted the rule. When the solver couldn't decide
+    /// a rule and fuzzing accepted it instead, this is
+    /// [Validation::Fuzz], taken from [ValidationResult::Unknown].
+    pub validation: Validation,
+    /// Time in seconds spent validating the rule.
+    pub validation_time: f64,
+    pub score: [i32; 5],
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -22,6 +184,10 @@ pub struct SerializedEq {
     lhs: String,
     rhs: String,
     bidirectional: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    guard: Option<Guard>,
 }
 
 impl<L: SynthLanguage + 'static> From<SerializedEq> for Equality<L> {
@@ -30,7 +196,12 @@ impl<L: SynthLanguage + 'static> From<SerializedEq> for Equality<L> {
         let rhs: Pattern<L> = ser.rhs.parse().unwrap();
         let lhs = L::instantiate(&lhs);
         let rhs = L::instantiate(&rhs);
-        Self::new(&lhs, &rhs).unwrap()
+        let mut eq = Self::new(&lhs, &rhs).unwrap();
+        if let Some(guard) = ser.guard {
+            eq = eq.with_guard(guard).unwrap();
+        }
+        eq.provenance = ser.provenance;
+        eq
     }
 }
 
@@ -40,13 +211,15 @@ impl<L: SynthLanguage> From<Equality<L>> for SerializedEq {
             lhs: eq.lhs.to_string(),
             rhs: eq.rhs.to_string(),
             bidirectional: eq.rewrites.len() > 1,
+            provenance: eq.provenance,
+            guard: eq.guard,
         }
     }
 }
 
 impl<L: SynthLanguage> Hash for Equality<L> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.name.hash(state);
+        self.key.hash(state);
     }
 }
 
@@ -58,7 +231,7 @@ impl<L: SynthLanguage> Display for Equality<L> {
 
 impl<L: SynthLanguage> PartialEq for Equality<L> {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+        self.key == other.key
     }
 }
 
@@ -106,7 +279,7 @@ impl<L: SynthLanguage> Applier<L, SynthAnalysis> for NotUndefined<L> {
             .data
             .cvec
             .iter()
-            .zip(&egraph[id].data.cvec)
+            .zip(egraph[id].data.cvec.iter())
             .enumerate()
         {
             match (a, b) {
@@ -150,6 +323,32 @@ impl<L: SynthLanguage> Applier<L, SynthAnalysis> for NotUndefined<L> {
     }
 }
 
+/// Only applies a conditional rule when the guard holds for every defined
+/// value in the cvec of the guarded variable.
+struct GuardCondition<L: SynthLanguage> {
+    var: Var,
+    pred: String,
+    phantom: PhantomData<L>,
+}
+
+impl<L: SynthLanguage> egg::Condition<L, SynthAnalysis> for GuardCondition<L> {
+    fn check(
+        &self,
+        egraph: &mut EGraph<L, SynthAnalysis>,
+        _eclass: Id,
+        subst: &Subst,
+    ) -> bool {
+        match subst.get(self.var) {
+            Some(id) => guard_holds::<L>(&self.pred, &egraph[*id].data.cvec),
+            None => false,
+        }
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        vec![self.var]
+    }
+}
+
 struct RandomCost {
     rng: Pcg32,
 }
@@ -187,7 +386,8 @@ impl<L: SynthLanguage> Equality<L> {
             // HACK: we need to manually remove quotes from the produced
             // string because the `symbolic_expression` library that `egg`
             // uses to display s-expressions adds them in a way that's very
-            // hard to remove without doing this hack.
+            // hard to remove without doing this hack. The name is only for
+            // display, equalities are identified by their `RuleKey`.
             let name = format!("{} => {}", lhs, rhs).replace("\"", "");
             let defined_rhs = NotUndefined {
                 name: name.clone(),
@@ -229,14 +429,18 @@ impl<L: SynthLanguage> Equality<L> {
             ((name, lhs, rhs, Some(rw)), (_, _, _, None))
             | ((_, _, _, None), (name, lhs, rhs, Some(rw))) => Some(Self {
                 name: name.into(),
+                key: RuleKey::new(&lhs, &rhs, None),
                 lhs,
                 rhs,
                 ids: None,
                 rewrites: vec![rw],
+                provenance: None,
+                guard: None,
             }),
             ((_, lhs, rhs, Some(rw1)), (_, _, _, Some(rw2))) => Some(Self {
                 // HACK: same as above
                 name: format!("{} <=> {}", lhs, rhs).replace("\"", "").into(),
+                key: RuleKey::new(&lhs, &rhs, None),
                 lhs,
                 rhs,
                 ids: None,
@@ -245,13 +449,141 @@ impl<L: SynthLanguage> Equality<L> {
                 } else {
                     vec![rw1, rw2]
                 },
+                provenance: None,
+                guard: None,
             }),
         }
     }
 
+    /// Create a conditional [Equality] from two expressions that are equal
+    /// whenever `pred` holds for the variable `sym`.
+    pub fn new_guarded(
+        e1: &RecExpr<L>,
+        e2: &RecExpr<L>,
+        sym: Symbol,
+        pred: &str,
+    ) -> Option<Self> {
+        let eq = Self::new(e1, e2)?;
+        // find the pattern variable that `new` gave to `sym` by generalizing
+        // the expressions again in the order that produced `eq.lhs`.
+        let var = [(e1, e2), (e2, e1)].iter().find_map(|(a, b)| {
+            let map = &mut HashMap::default();
+            let lhs = L::generalize(a, map);
+            let rhs = L::generalize(b, map);
+            (lhs.to_string() == eq.lhs.to_string()
+                && rhs.to_string() == eq.rhs.to_string())
+            .then(|| map.get(&sym).copied())
+            .flatten()
+        })?;
+        eq.with_guard(Guard {
+            var: var.to_string(),
+            pred: pred.to_string(),
+        })
+    }
+
+    /// Make this equality conditional on `guard`. Only the directions whose
+    /// left hand side binds the guarded variable are kept, so this returns
+    /// `None` if neither side mentions it.
+    pub fn with_guard(mut self, guard: Guard) -> Option<Self> {
+        let var: Var = guard.var.parse().ok()?;
+        let mut directions = vec![(&self.lhs, &self.rhs)];
+        if self.rewrites.len() > 1 {
+            directions.push((&self.rhs, &self.lhs));
+        }
+        let rewrites: Vec<Rewrite<L, SynthAnalysis>> = directions
+            .into_iter()
+            .filter(|(lhs, _)| lhs.vars().contains(&var))
+            .flat_map(|(lhs, rhs)| {
+                // HACK: same as in `new`
+                let name = format!("{} => {} if {}", lhs, rhs, guard)
+                    .replace("\"", "");
+                let applier = ConditionalApplier {
+                    condition: GuardCondition {
+                        var,
+                        pred: guard.pred.clone(),
+                        phantom: PhantomData,
+                    },
+                    applier: NotUndefined {
+                        name: name.clone(),
+                        rhs: rhs.clone(),
+                    },
+                };
+                Rewrite::new(name, lhs.clone(), applier).ok()
+            })
+            .collect();
+        if rewrites.is_empty() {
+            return None;
+        }
+        self.name = format!("{} if {}", self.name, guard).into();
+        self.key = RuleKey::new(&self.lhs, &self.rhs, Some(&guard));
+        self.rewrites = rewrites;
+        self.guard = Some(guard);
+        Some(self)
+    }
+
     /// Assign a score to this Equality using heursitics mentioned in the Ruler paper
     /// (page 11, footnote 5).
     pub fn score(&self) -> impl Ord + Debug {
         L::score(&self.lhs, &self.rhs)
     }
+
+    /// Record that this equality was learned in the given iteration and
+    /// accepted by `validation`, the method that actually accepted it
+    /// rather than the one that was tried first.
+    pub fn learned(
+        &mut self,
+        outer_iter: usize,
+        inner_iter: usize,
+        validation: Validation,
+        validation_time: f64,
+    ) {
+        self.provenance = Some(Provenance {
+            outer_iter,
+            inner_iter,
+            validation,
+            validation_time,
+            score: L::score(&self.lhs, &self.rhs),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egg::SymbolLang;
+
+    fn key(lhs: &str, rhs: &str, guard: Option<&Guard>) -> RuleKey {
+        let lhs: Pattern<SymbolLang> = lhs.parse().unwrap();
+        let rhs: Pattern<SymbolLang> = rhs.parse().unwrap();
+        RuleKey::new(&lhs, &rhs, guard)
+    }
+
+    #[test]
+    fn test_rule_key() {
+        let comm = key("(+ ?a ?b)", "(+ ?b ?a)", None);
+        assert_eq!(comm.to_string(), "(+ ?0 ?1) = (+ ?1 ?0)");
+        assert_eq!(comm, key("(+ ?x ?y)", "(+ ?y ?x)", None));
+        assert_eq!(comm, key("(+ ?b ?a)", "(+ ?a ?b)", None));
+
+        // either orientation gives the same key
+        let zero = key("(* ?a 0)", "0", None);
+        assert_eq!(zero, key("0", "(* ?b 0)", None));
+        assert_eq!(zero.to_string(), "(* ?0 0) = 0");
+
+        // different rules have different keys
+        assert_ne!(comm, key("(+ ?a ?b)", "(+ ?a ?b)", None));
+        assert_ne!(comm, key("(* ?a ?b)", "(* ?b ?a)", None));
+    }
+
+    #[test]
+    fn test_rule_key_guard() {
+        let guard = |var: &str| Guard {
+            var: var.into(),
+            pred: "!= 0".into(),
+        };
+        let div = key("(/ ?a ?a)", "1", Some(&guard("?a")));
+        assert_eq!(div.to_string(), "(/ ?0 ?0) = 1 if ?0 != 0");
+        assert_eq!(div, key("1", "(/ ?x ?x)", Some(&guard("?x"))));
+        assert_ne!(div, key("(/ ?a ?a)", "1", None));
+    }
 }
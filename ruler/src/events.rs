@@ -0,0 +1,114 @@
+//! Structured progress events, written as JSON lines.
+//!
+//! Every line is an object with the time since synthesis started, the
+//! current outer and inner iteration, and an `event` tag with the
+//! event-specific fields. The file is flushed after every event so that it
+//! can be followed while synthesis is running.
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    sync::Mutex,
+};
+
+use crate::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    IterationStart,
+    /// Terms from the layer were added to the egraph.
+    Layer {
+        terms: usize,
+    },
+    #[serde(rename = "egraph")]
+    EGraph {
+        nodes: usize,
+        classes: usize,
+    },
+    /// Candidates found by cvec matching, before and after removing known
+    /// bad rules.
+    Candidates {
+        found: usize,
+        remaining: usize,
+    },
+    ChooseEqs {
+        accepted: usize,
+        rejected: usize,
+    },
+    /// Time in seconds spent in each phase of an inner iteration.
+    Timing {
+        run_rewrites: f64,
+        rule_discovery: f64,
+        rule_minimization: f64,
+    },
+    Checkpoint {
+        path: PathBuf,
+        seconds: f64,
+    },
+    Done {
+        num_rules: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct EventLine<'a> {
+    time: f64,
+    outer_iter: usize,
+    inner_iter: usize,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Where events are written. Cloning the log shares the underlying file.
+#[derive(Clone, Default)]
+pub(crate) struct EventLog {
+    file: Option<Arc<Mutex<BufWriter<std::fs::File>>>>,
+}
+
+impl EventLog {
+    /// Open `path` for appending, so that a resumed run continues the
+    /// events of the run it resumed from.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(BufWriter::new(file)))),
+        })
+    }
+
+    fn write(&self, line: &EventLine) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            serde_json::to_writer(&mut *file, line)?;
+            writeln!(file)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<L: SynthLanguage> Synthesizer<L, Init> {
+    /// Start writing events to `SynthParams::events_file`, if it is set.
+    pub(crate) fn open_event_log(&mut self) {
+        if let Some(path) = &self.params.events_file {
+            match EventLog::open(path) {
+                Ok(events) => self.events = events,
+                Err(e) => {
+                    log::warn!("Failed to open event file {path:?}: {e}")
+                }
+            }
+        }
+    }
+
+    /// Record a progress event.
+    pub(crate) fn event(&self, event: Event) {
+        let line = EventLine {
+            time: self.start_time.elapsed().as_secs_f64(),
+            outer_iter: self.outer_iter,
+            inner_iter: self.inner_iter,
+            event: &event,
+        };
+        if let Err(e) = self.events.write(&line) {
+            log::warn!("Failed to write event: {e}");
+        }
+    }
+}
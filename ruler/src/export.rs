@@ -0,0 +1,305 @@
+//! Export rulesets to the formats of other rewriting tools.
+use std::{fmt::Write, io, str::FromStr};
+
+use crate::*;
+
+/// Formats that a ruleset can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// egglog `rewrite` and `birewrite` declarations, preceded by a
+    /// `datatype` for the operators that the rules use.
+    Egglog,
+    /// One `lhs ==> rhs` rule per line, followed by `if guard` for
+    /// conditional rules. Bidirectional rules are written once in each
+    /// direction.
+    Text,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "egglog" => Ok(ExportFormat::Egglog),
+            "text" => Ok(ExportFormat::Text),
+            _ => Err(format!(
+                "unknown export format '{s}', expected 'egglog' or 'text'"
+            )),
+        }
+    }
+}
+
+/// Export the ruleset at `input` to `out` in `format`.
+pub fn export<L: SynthLanguage>(
+    input: &Path,
+    out: &Path,
+    format: ExportFormat,
+) -> io::Result<()> {
+    let eqs = read_ruleset::<L>(input)?;
+    let contents = match format {
+        ExportFormat::Egglog => to_egglog(&eqs),
+        ExportFormat::Text => to_text(&eqs),
+    };
+    std::fs::write(out, contents)?;
+    log::info!("Exported {} rules to {out:?}", eqs.len());
+    Ok(())
+}
+
+/// Write `eqs` as `lhs ==> rhs` lines.
+pub fn to_text<L: SynthLanguage>(eqs: &[Equality<L>]) -> String {
+    let mut out = String::new();
+    for eq in eqs {
+        let guard = match &eq.guard {
+            Some(guard) => format!(" if {guard}"),
+            None => String::new(),
+        };
+        writeln!(out, "{} ==> {}{guard}", eq.lhs, eq.rhs).unwrap();
+        if eq.rewrites.len() > 1 {
+            writeln!(out, "{} ==> {}{guard}", eq.rhs, eq.lhs).unwrap();
+        }
+    }
+    out
+}
+
+/// Write `eqs` as an egglog program. egglog constructors have a fixed
+/// arity, so an operator that is used with several arities gets one
+/// constructor per arity. The meaning of guards is up to the language, so
+/// conditional rules are left out.
+pub fn to_egglog<L: SynthLanguage>(eqs: &[Equality<L>]) -> String {
+    let mut sig = EgglogSignature::default();
+    for eq in eqs {
+        for pat in [&eq.lhs, &eq.rhs] {
+            sig.add_pattern(pat);
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "(datatype {EGGLOG_SORT}").unwrap();
+    writeln!(out, "  (Num i64)").unwrap();
+    writeln!(out, "  (Bool bool)").unwrap();
+    writeln!(out, "  (Const String)").unwrap();
+    writeln!(out, "  (Sym String)").unwrap();
+    for (_, arity, name) in &sig.constructors {
+        let args = vec![EGGLOG_SORT; *arity].join(" ");
+        writeln!(out, "  ({name} {args})").unwrap();
+    }
+    writeln!(out, ")").unwrap();
+    writeln!(out).unwrap();
+
+    for eq in eqs {
+        if eq.guard.is_some() {
+            writeln!(out, ";; skipped conditional rule: {}", eq.name).unwrap();
+            continue;
+        }
+        let lhs = sig.pattern_to_egglog(&eq.lhs);
+        let rhs = sig.pattern_to_egglog(&eq.rhs);
+        let cmd = if eq.rewrites.len() > 1 {
+            "birewrite"
+        } else {
+            "rewrite"
+        };
+        writeln!(out, ";; {}", eq.name).unwrap();
+        writeln!(out, "({cmd} {lhs} {rhs})").unwrap();
+    }
+    out
+}
+
+/// The sort of every term in the generated datatype.
+const EGGLOG_SORT: &str = "Expr";
+
+/// Constructor names for the operators of a language.
+#[derive(Default)]
+struct EgglogSignature {
+    /// `(op, arity, constructor name)`, in the order they were first used.
+    constructors: Vec<(String, usize, String)>,
+}
+
+impl EgglogSignature {
+    fn add_pattern<L: SynthLanguage>(&mut self, pat: &Pattern<L>) {
+        for node in pat.ast.as_ref() {
+            if let ENodeOrVar::ENode(n) = node {
+                if !n.is_leaf() {
+                    self.add_op(&n.to_string(), n.len());
+                }
+            }
+        }
+    }
+
+    fn add_op(&mut self, op: &str, arity: usize) {
+        if self.lookup(op, arity).is_some() {
+            return;
+        }
+        // only mention the arity when the operator is variadic
+        let base = if self.constructors.iter().any(|(o, _, _)| o == op) {
+            format!("{}{arity}", mangle(op))
+        } else {
+            mangle(op)
+        };
+        let mut name = base.clone();
+        let mut i = 1;
+        while ["Num", "Bool", "Const", "Sym", EGGLOG_SORT].contains(&&*name)
+            || self.constructors.iter().any(|(_, _, n)| *n == name)
+        {
+            name = format!("{base}_{i}");
+            i += 1;
+        }
+        self.constructors.push((op.to_string(), arity, name));
+    }
+
+    fn lookup(&self, op: &str, arity: usize) -> Option<&str> {
+        self.constructors
+            .iter()
+            .find(|(o, a, _)| o == op && *a == arity)
+            .map(|(_, _, name)| name.as_str())
+    }
+
+    fn pattern_to_egglog<L: SynthLanguage>(&self, pat: &Pattern<L>) -> String {
+        let nodes = pat.ast.as_ref();
+        self.node_to_egglog(nodes, nodes.len() - 1)
+    }
+
+    fn node_to_egglog<L: SynthLanguage>(
+        &self,
+        nodes: &[ENodeOrVar<L>],
+        i: usize,
+    ) -> String {
+        match &nodes[i] {
+            ENodeOrVar::Var(v) => v.to_string().trim_start_matches('?').into(),
+            ENodeOrVar::ENode(n) if n.is_leaf() => leaf_to_egglog(n),
+            ENodeOrVar::ENode(n) => {
+                let name = self.lookup(&n.to_string(), n.len()).unwrap();
+                let args: Vec<String> = n
+                    .children()
+                    .iter()
+                    .map(|id| self.node_to_egglog(nodes, usize::from(*id)))
+                    .collect();
+                format!("({name} {})", args.join(" "))
+            }
+        }
+    }
+}
+
+fn leaf_to_egglog<L: SynthLanguage>(node: &L) -> String {
+    let s = node.to_string();
+    if node.is_constant() {
+        if let Ok(n) = s.parse::<i64>() {
+            return format!("(Num {n})");
+        }
+        if let Ok(b) = s.parse::<bool>() {
+            return format!("(Bool {b})");
+        }
+        format!("(Const {s:?})")
+    } else {
+        format!("(Sym {s:?})")
+    }
+}
+
+/// Turn an operator into a constructor name. Operators that are made of
+/// punctuation are named after what they usually mean.
+fn mangle(op: &str) -> String {
+    let named = match op {
+        "+" => "Add",
+        "-" => "Sub",
+        "*" => "Mul",
+        "/" => "Div",
+        "%" => "Mod",
+        "<" => "Lt",
+        ">" => "Gt",
+        "<=" => "Le",
+        ">=" => "Ge",
+        "=" | "==" => "Eq",
+        "!=" => "Ne",
+        "&&" => "And",
+        "||" => "Or",
+        "!" => "Not",
+        "~" => "BitNot",
+        "&" => "BitAnd",
+        "|" => "BitOr",
+        "^" => "Xor",
+        "<<" => "Shl",
+        ">>" => "Shr",
+        _ => "",
+    };
+    if !named.is_empty() {
+        return named.to_string();
+    }
+
+    // otherwise CamelCase the alphanumeric parts of the operator
+    let mut name = String::new();
+    for part in op.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            name.push(first.to_ascii_uppercase());
+            name.extend(chars);
+        }
+    }
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert_str(0, "Op");
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_lang::Math;
+
+    fn eq(lhs: &str, rhs: &str) -> Equality<Math> {
+        Equality::new(&lhs.parse().unwrap(), &rhs.parse().unwrap()).unwrap()
+    }
+
+    fn ruleset() -> Vec<Equality<Math>> {
+        let guard = Guard {
+            var: "?a".into(),
+            pred: "!= 0".into(),
+        };
+        vec![
+            eq("(* a 0)", "0"),
+            eq("(+ a (- b))", "(- a b)"),
+            eq("(* a 0)", "0").with_guard(guard).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_to_text() {
+        assert_eq!(
+            to_text(&ruleset()),
+            "(* ?a 0) ==> 0\n\
+             (- ?a ?b) ==> (+ ?a (- ?b))\n\
+             (+ ?a (- ?b)) ==> (- ?a ?b)\n\
+             (* ?a 0) ==> 0 if ?a != 0\n"
+        );
+    }
+
+    #[test]
+    fn test_to_egglog() {
+        assert_eq!(
+            to_egglog(&ruleset()),
+            "(datatype Expr\n\
+            \x20 (Num i64)\n\
+            \x20 (Bool bool)\n\
+            \x20 (Const String)\n\
+            \x20 (Sym String)\n\
+            \x20 (Mul Expr Expr)\n\
+            \x20 (Sub Expr Expr)\n\
+            \x20 (Sub1 Expr)\n\
+            \x20 (Add Expr Expr)\n\
+            )\n\
+            \n\
+            ;; (* ?a 0) => 0\n\
+            (rewrite (Mul a (Num 0)) (Num 0))\n\
+            ;; (- ?a ?b) <=> (+ ?a (- ?b))\n\
+            (birewrite (Sub a b) (Add a (Sub1 b)))\n\
+            ;; skipped conditional rule: (* ?a 0) => 0 if ?a != 0\n"
+        );
+    }
+
+    #[test]
+    fn test_mangle() {
+        assert_eq!(mangle("+"), "Add");
+        assert_eq!(mangle("VecMAC"), "VecMAC");
+        assert_eq!(mangle("vec-add"), "VecAdd");
+        assert_eq!(mangle("2x"), "Op2x");
+        assert_eq!(mangle("@"), "Op");
+    }
+}
@@ -10,14 +10,14 @@ use egg::{
     Symbol, Var,
 };
 use itertools::Itertools;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
 use rand_pcg::Pcg32;
+use rayon::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::borrow::{Borrow, Cow};
-use std::fs;
 use std::hash::Hash;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{
     fmt::{Debug, Display},
     marker::PhantomData,
@@ -26,10 +26,22 @@ use std::{
 };
 use std::{hash::BuildHasherDefault, sync::Arc};
 
+mod checkpoint;
+#[cfg(feature = "cli")]
+mod cli;
 mod convert_sexp;
 mod derive;
 mod equality;
+mod events;
+mod export;
+mod minimize;
+mod parallel;
+mod rejected;
+mod shard;
+#[cfg(test)]
+mod test_lang;
 mod util;
+mod workload;
 
 /// Faster hashMap implementation used in rustc
 pub type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
@@ -41,9 +53,22 @@ pub type HashSet<K> = rustc_hash::FxHashSet<K>;
 pub type IndexMap<K, V> =
     indexmap::IndexMap<K, V, BuildHasherDefault<rustc_hash::FxHasher>>;
 
+pub use checkpoint::{
+    latest_checkpoint, list_checkpoints, CheckpointError, CHECKPOINT_VERSION,
+};
+pub use convert_sexp::{convert, ConvertParams};
+pub use derive::{derive, derive_pairs, Derivation, DeriveParams, Pair};
 pub use egg;
 pub use equality::*;
+pub use events::Event;
+use events::EventLog;
+pub use export::{export, to_egglog, to_text, ExportFormat};
+pub use minimize::{minimize, minimize_eqs, MinimizeParams, Minimized};
+use parallel::{PrecomputedCVecs, PRECOMPUTE_BATCH_SIZE};
+pub use rejected::{read_rejected, write_rejected, Rejection};
+pub use shard::{merge, run_shards, MergeParams, Shard};
 pub use util::*;
+use workload::{LayerTerm, Workload};
 
 /// Return the `i`th letter from the English alphabet.
 pub fn letter(i: usize) -> &'static str {
@@ -58,11 +83,24 @@ pub struct SynthAnalysis {
     /// Length of cvec or characteristic vector.
     /// All cvecs have the same length.    
     pub cvec_len: usize,
+    /// Cvecs of the layer that is being added, evaluated ahead of time.
+    #[serde(skip)]
+    pub(crate) precomputed: PrecomputedCVecs,
+}
+
+impl SynthAnalysis {
+    /// An analysis for cvecs of length `cvec_len`.
+    pub fn new(cvec_len: usize) -> Self {
+        Self {
+            cvec_len,
+            precomputed: PrecomputedCVecs::default(),
+        }
+    }
 }
 
 impl Default for SynthAnalysis {
     fn default() -> Self {
-        Self { cvec_len: 10 }
+        Self::new(10)
     }
 }
 
@@ -87,12 +125,14 @@ pub trait SynthLanguage:
         + Debug
         + Display
         + Serialize
-        + DeserializeOwned;
-    type Config: Clone + Serialize + DeserializeOwned;
+        + DeserializeOwned
+        + Send
+        + Sync;
+    type Config: Clone + Serialize + DeserializeOwned + Send + Sync;
 
     fn eval<'a, F>(&'a self, cvec_len: usize, f: F) -> CVec<Self>
     where
-        F: FnMut(&'a Id) -> &'a CVec<Self>;
+        F: FnMut(&'a Id) -> &'a [Option<Self::Constant>];
 
     fn to_var(&self) -> Option<Symbol>;
     fn mk_var(sym: egg::Symbol) -> Self;
@@ -209,6 +249,17 @@ pub trait SynthLanguage:
         ]
     }
 
+    /// Domain specific terms that are appended to [SynthLanguage::score]
+    /// when ranking candidates in `choose_eqs`. Like the other components,
+    /// larger is better.
+    fn domain_score(
+        _synth: &Synthesizer<Self, Init>,
+        _lhs: &Pattern<Self>,
+        _rhs: &Pattern<Self>,
+    ) -> Vec<i32> {
+        vec![]
+    }
+
     /// Initialize an egraph with variables and interesting constants from the domain.
     fn init_synth(synth: &mut Synthesizer<Self, Uninit>);
 
@@ -228,7 +279,7 @@ pub trait SynthLanguage:
         ctx: &HashMap<Var, CVec<Self>>,
         cvec_len: usize,
     ) -> CVec<Self> {
-        let mut buf: Vec<Cow<CVec<Self>>> = vec![];
+        let mut buf: Vec<Cow<[Option<Self::Constant>]>> = vec![];
         for enode in pat.ast.as_ref().iter() {
             match enode {
                 ENodeOrVar::ENode(enode) => {
@@ -237,7 +288,7 @@ pub trait SynthLanguage:
                     buf.push(Cow::Owned(cvec));
                 }
                 ENodeOrVar::Var(var) => {
-                    buf.push(Cow::Borrowed(&ctx[var]));
+                    buf.push(Cow::Borrowed(ctx[var].as_slice()));
                 }
             }
         }
@@ -245,11 +296,46 @@ pub trait SynthLanguage:
     }
 
     /// Domain specific rule validation.
+    ///
+    /// Candidates are validated in parallel, so this only gets shared access
+    /// to the synthesizer. Any randomness should come from `rng`, which is
+    /// seeded per candidate from the synthesizer's rng so that results are
+    /// deterministic for a given seed. Rules whose validity can't be decided
+    /// should be reported as [ValidationResult::Unknown] so that they are
+    /// counted in the report.
     fn is_valid(
-        synth: &mut Synthesizer<Self, Init>,
+        synth: &Synthesizer<Self, Init>,
+        rng: &mut Pcg32,
         lhs: &Pattern<Self>,
         rhs: &Pattern<Self>,
-    ) -> bool;
+    ) -> ValidationResult;
+
+    /// The predicates that conditional rules can be guarded by, for example
+    /// `!= 0` or `> 0`. Candidates whose cvecs only agree on some values are
+    /// guarded with one of these when it explains where they agree. No
+    /// conditional rules are inferred by default.
+    fn guard_predicates(_synth: &Synthesizer<Self, Init>) -> Vec<String> {
+        vec![]
+    }
+
+    /// Evaluate one of [SynthLanguage::guard_predicates] on a constant.
+    fn eval_guard(_pred: &str, _c: &Self::Constant) -> bool {
+        false
+    }
+
+    /// Domain specific validation of a conditional rule, which only has to
+    /// hold when `guard` does.
+    fn is_valid_guarded(
+        _synth: &Synthesizer<Self, Init>,
+        _rng: &mut Pcg32,
+        _lhs: &Pattern<Self>,
+        _rhs: &Pattern<Self>,
+        _guard: &Guard,
+    ) -> ValidationResult {
+        ValidationResult::Invalid(
+            "conditional rules aren't supported by this language".into(),
+        )
+    }
 
     /// helper functions to convert CVC4 rewrites to Ruler's rule syntax.
     fn convert_parse(s: &str) -> RecExpr<Self> {
@@ -281,6 +367,8 @@ pub trait SynthLanguage:
     }
 }
 
+/// A command line interface for rule synthesis, available for every
+/// [SynthLanguage] whose config has a default.
 #[cfg(feature = "cli")]
 pub trait Main {
     fn main();
@@ -292,27 +380,43 @@ where
     T::Config: Default,
 {
     /// Entry point. Use the `synth` argument from the command line
-    /// for rule synthesis.
+    /// for rule synthesis, and `derive`, `convert-sexp` or `minimize` to
+    /// work with existing rulesets.
     fn main() {
         let _ = env_logger::builder().try_init();
         match cli::Command::cli() {
-            cli::Command::Synth(params) => {
-                let params: SynthParams = params.into();
+            cli::Command::Synth(opts) => {
+                let checkpoint = opts.checkpoint.clone();
+                let resume = opts.resume;
+                let params: SynthParams = opts.into();
+                if let Err(e) = params.check_workload::<Self>() {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
                 let outfile = params.outfile.clone();
-                let syn = Synthesizer::<Self, _>::new(params.clone()).init();
+                let mut syn =
+                    Synthesizer::<Self, _>::new(params.clone()).init();
+                // an explicit checkpoint takes precedence over resuming
+                let restored = match checkpoint {
+                    Some(path) => syn.load_checkpoint(&path),
+                    None if resume => syn.resume().map(|_| ()),
+                    None => Ok(()),
+                };
+                restored.unwrap_or_else(|e| {
+                    panic!("Failed to restore a checkpoint: {}", e)
+                });
                 let report: Report<Self> =
                     Self::post_process(&params, syn.run());
-                let file = std::fs::File::create(&outfile)
-                    .unwrap_or_else(|_| panic!("Failed to open '{}'", outfile));
-                serde_json::to_writer_pretty(file, &report)
-                    .expect("failed to write json");
-            }
-            cli::Command::Derive(params) => {
-                derive::derive::<Self>(params.into())
-            }
-            cli::Command::ConvertSexp(params) => {
-                convert_sexp::convert::<Self>(params.into())
+                write_json_atomic(Path::new(&outfile), &report).unwrap_or_else(
+                    |e| panic!("Failed to write '{}': {}", outfile, e),
+                );
             }
+            cli::Command::Derive(params) => derive::<Self>(params.into()),
+            cli::Command::ConvertSexp(params) => convert::<Self>(params.into()),
+            cli::Command::Minimize(params) => minimize::<Self>(params.into())
+                .unwrap_or_else(|e| {
+                    panic!("Failed to minimize the ruleset: {}", e)
+                }),
         }
     }
 }
@@ -334,15 +438,24 @@ pub struct Synthesizer<L: SynthLanguage, State> {
     initial_egraph: EGraph<L, SynthAnalysis>,
     pub equalities: EqualityMap<L>,
     pub smt_unknown: usize,
+    /// Candidates whose validity couldn't be decided.
+    #[serde(default)]
+    unknown_rules: EqualityMap<L>,
     #[serde(skip, default = "Instant::now")]
     start_time: Instant,
     #[serde(skip, default = "Instant::now")]
     last_checked: Instant,
     outer_iter: usize,
     inner_iter: usize,
-    poison_rules: HashSet<Equality<L>>,
+    /// Candidates that failed validation.
+    rejected: IndexMap<RuleKey, Rejection>,
+    /// Keys of the equalities that were loaded from prior rulesets.
+    #[serde(default)]
+    inherited: HashSet<RuleKey>,
     #[serde(skip)]
     inner_restored: bool,
+    #[serde(skip)]
+    events: EventLog,
     phantom_state: PhantomData<State>,
 }
 
@@ -354,20 +467,49 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Uninit> {
             initial_egraph: Default::default(),
             equalities: Default::default(),
             smt_unknown: 0,
+            unknown_rules: Default::default(),
             params,
             start_time: Instant::now(),
             last_checked: Instant::now(),
             lang_config: data,
             outer_iter: 1,
             inner_iter: 0,
-            poison_rules: HashSet::default(),
+            rejected: IndexMap::default(),
+            inherited: HashSet::default(),
             inner_restored: true,
+            events: EventLog::default(),
             phantom_state: PhantomData,
         }
     }
 
+    /// Add the rules of a ruleset written by an earlier run as trusted
+    /// equalities. They are used for rewriting and for minimizing candidates,
+    /// but are reported separately from newly learned rules. Returns the
+    /// number of rules that were added.
+    pub fn load_prior_ruleset(
+        &mut self,
+        path: &Path,
+    ) -> std::io::Result<usize> {
+        let mut added = 0;
+        for eq in read_ruleset(path)? {
+            if !self.equalities.contains_key(&eq.key) {
+                self.inherited.insert(eq.key.clone());
+                self.equalities.insert(eq.key.clone(), eq);
+                added += 1;
+            }
+        }
+        log::info!("Inherited {added} rules from {path:?}");
+        Ok(added)
+    }
+
     pub fn init(mut self) -> Synthesizer<L, Init> {
         L::init_synth(&mut self);
+        self.load_rejected();
+        for path in self.params.prior_rulesets.clone() {
+            if let Err(e) = self.load_prior_ruleset(&path) {
+                log::warn!("Failed to load prior ruleset {path:?}: {e}");
+            }
+        }
         self.initial_egraph = self.egraph.clone();
         self.phantom_state = PhantomData;
         Synthesizer {
@@ -378,13 +520,16 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Uninit> {
             initial_egraph: self.initial_egraph,
             equalities: self.equalities,
             smt_unknown: self.smt_unknown,
+            unknown_rules: self.unknown_rules,
             params: self.params,
             start_time: self.start_time,
             last_checked: self.last_checked,
             lang_config: self.lang_config,
             outer_iter: self.outer_iter,
-            poison_rules: self.poison_rules,
+            rejected: self.rejected,
+            inherited: self.inherited,
             inner_restored: self.inner_restored,
+            events: self.events,
             inner_iter: self.inner_iter,
         }
     }
@@ -424,64 +569,6 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
         }
     }
 
-    fn save_checkpoint(&self) {
-        if self.params.enable_checkpointing {
-            let start = Instant::now();
-            log::info!("Starting a checkpoint!");
-
-            let chkpt_filename = format!(
-                "ruler_out{}_in{}.chkpt",
-                self.outer_iter, self.inner_iter
-            );
-            let file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(chkpt_filename)
-                .expect("Failed to open file");
-
-            // serde_json::to_writer(file, &self).expect("Failed to serialize.");
-            ciborium::ser::into_writer(&self, file)
-                .expect("Failed to write checkpoint");
-
-            log::info!(
-                "Finished writing a checkpoint in {:?}",
-                start.elapsed()
-            );
-        } else {
-            log::info!("Skipping checkpoint!");
-        }
-    }
-
-    pub fn load_checkpoint(&mut self, filename: &Path) {
-        let start = Instant::now();
-        log::info!("Loading checkpoint: {filename:?}");
-        let chkpt_file = fs::File::open(filename).expect("Failed to open file");
-        // let copy: Self = serde_json::from_reader(chkpt_file)
-        //     .expect("Failed to derserialize");
-        let copy = ciborium::de::from_reader(chkpt_file)
-            .expect("Failed to deserialize");
-        // copy everything besides the params
-        *self = Self {
-            params: self.params.clone(),
-            ..copy
-        };
-        // we save the index of the finished iter, so this is off by one.
-        // incrementing it corrects it
-        self.inner_iter += 1;
-
-        log::info!("Loaded in {:?}", start.elapsed());
-
-        // serialized egraphs don't necessarily maintain the correct
-        // invariants. reubild them so that they are correct
-        log::info!("Rebuilding egraphs...");
-        self.egraph.rebuild();
-        self.initial_egraph.rebuild();
-        log::info!("Done");
-
-        self.inner_restored = false;
-    }
-
     /// Get the eclass ids for all eclasses in the egraph.
     pub fn ids(&self) -> impl '_ + Iterator<Item = Id> {
         self.egraph.classes().map(|c| c.id)
@@ -529,7 +616,7 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
         runner = if self.params.no_conditionals {
             egraph.analysis.cvec_len = 0;
             for c in egraph.classes_mut() {
-                c.data.cvec.truncate(0);
+                c.data.cvec = Arc::from([]);
             }
             runner.with_egraph(egraph).with_hook(|r| {
                 for c in r.egraph.classes_mut() {
@@ -611,7 +698,9 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
         let mut new_eqs = EqualityMap::default();
         let extract = Extractor::new(&self.egraph, AstSize);
 
-        let compare = |cvec1: &CVec<L>, cvec2: &CVec<L>| -> bool {
+        let compare = |cvec1: &[Option<L::Constant>],
+                       cvec2: &[Option<L::Constant>]|
+         -> bool {
             // keep track of number of pairs that match
             // we need at least 1 pair to say that the cvecs
             // are equal
@@ -639,7 +728,7 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
                         let (_, e2) = extract.find_best(id2);
                         if let Some(mut eq) = Equality::new(&e1, &e2) {
                             eq.ids = Some((id1, id2));
-                            new_eqs.insert(eq.name.clone(), eq);
+                            new_eqs.insert(eq.key.clone(), eq);
                         }
                     }
                 }
@@ -647,61 +736,175 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
         }
         log::info!("done");
 
+        let predicates = L::guard_predicates(self);
+        if !predicates.is_empty() {
+            self.cvec_match_guarded(&predicates, &extract, &mut new_eqs);
+        }
+
         new_eqs.retain(|k, _v| !self.equalities.contains_key(k));
         new_eqs
     }
 
+    /// Find conditional candidates: pairs of e-classes whose cvecs agree
+    /// exactly on the values where one of `predicates` holds for a variable
+    /// that appears in them. For each guard, the classes are bucketed by
+    /// their cvec restricted to the values where the guard holds, like
+    /// [Self::cvec_match] does with whole cvecs, and the first guard that
+    /// explains a pair is used.
+    fn cvec_match_guarded(
+        &self,
+        predicates: &[String],
+        extract: &Extractor<AstSize, L, SynthAnalysis>,
+        new_eqs: &mut EqualityMap<L>,
+    ) {
+        // for each variable and predicate, the values where the predicate
+        // holds
+        let mut guards: Vec<(Symbol, &str, Vec<bool>)> = vec![];
+        for class in self.egraph.classes() {
+            if let Some(sym) = class.nodes.iter().find_map(|n| n.to_var()) {
+                for pred in predicates {
+                    let holds = class
+                        .data
+                        .cvec
+                        .iter()
+                        .map(|v| {
+                            v.as_ref().map_or(false, |c| L::eval_guard(pred, c))
+                        })
+                        .collect();
+                    guards.push((sym, pred.as_str(), holds));
+                }
+            }
+        }
+
+        let ids: Vec<Id> = self
+            .ids()
+            .filter(|id| {
+                !self.egraph[*id].data.cvec.iter().all(|v| v.is_none())
+            })
+            .collect();
+        let before = new_eqs.len();
+        let mut matched: HashSet<(Id, Id)> = HashSet::default();
+        for (sym, pred, holds) in &guards {
+            // the cvec of each class where the guard holds. Classes in the
+            // same bucket agree wherever the guard holds.
+            let mut by_masked: IndexMap<Vec<&Option<L::Constant>>, Vec<Id>> =
+                IndexMap::default();
+            for &id in &ids {
+                let masked = holds
+                    .iter()
+                    .zip(self.egraph[id].data.cvec.iter())
+                    .filter(|(holds, _)| **holds)
+                    .map(|(_, v)| v)
+                    .collect();
+                by_masked.entry(masked).or_default().push(id);
+            }
+            for (masked, bucket) in &by_masked {
+                // the guard has to hold somewhere that the sides are defined
+                if masked.iter().all(|v| v.is_none()) {
+                    continue;
+                }
+                for (i, &id1) in bucket.iter().enumerate() {
+                    for &id2 in &bucket[i + 1..] {
+                        if matched.contains(&(id1, id2))
+                            || self.egraph[id1].data.cvec
+                                == self.egraph[id2].data.cvec
+                        {
+                            continue;
+                        }
+                        let (_, e1) = extract.find_best(id1);
+                        let (_, e2) = extract.find_best(id2);
+                        // `new_guarded` fails when the guarded variable
+                        // doesn't appear in the terms
+                        if let Some(mut eq) =
+                            Equality::new_guarded(&e1, &e2, *sym, pred)
+                        {
+                            eq.ids = Some((id1, id2));
+                            new_eqs.insert(eq.key.clone(), eq);
+                            matched.insert((id1, id2));
+                        }
+                    }
+                }
+            }
+        }
+        log::info!("found {} conditional candidates", new_eqs.len() - before);
+    }
+
     /// Generate potential rewrite rule candidates by cvec_matching.
-    /// This is a more efficient implementation that hashes the eclasses by their cvecs.
+    /// This is a more efficient implementation that takes the eclasses
+    /// bucketed by their cvecs from [Self::intern_cvecs].
     ///
     /// Note that this is only used when conditional rewrite rule inference is disabled.
     #[inline(never)]
-    fn cvec_match(&self) -> (EqualityMap<L>, Vec<Vec<Id>>) {
-        // build the cvec matching data structure
-        let mut by_cvec: IndexMap<&CVec<L>, Vec<Id>> = IndexMap::default();
-
-        for id in self.ids() {
-            let class = &self.egraph[id];
-            if class.data.is_defined() {
-                by_cvec.entry(&class.data.cvec).or_default().push(class.id);
-            }
-        }
+    fn cvec_match(
+        &self,
+        buckets: &[Vec<Id>],
+    ) -> (EqualityMap<L>, Vec<Vec<Id>>) {
+        // classes in the same bucket have equal cvecs, so the cvec of the
+        // first one says whether they are defined
+        let by_cvec: Vec<&Vec<Id>> = buckets
+            .iter()
+            .filter(|ids| self.egraph[ids[0]].data.is_defined())
+            .collect();
+        debug_assert!(
+            by_cvec.iter().all(|ids| ids.iter().all(|id| Arc::ptr_eq(
+                &self.egraph[*id].data.cvec,
+                &self.egraph[ids[0]].data.cvec
+            ))),
+            "cvecs have changed since they were interned"
+        );
 
         log::info!("(cvec_match) # unique cvecs: {}", by_cvec.len());
 
-        let mut new_eqs = EqualityMap::default();
+        // find the candidates of each bucket in parallel, and then add them
+        // in the order of the buckets
         let extract = Extractor::new(&self.egraph, AstSize);
-        for ids in by_cvec.values() {
-            if self.params.linear_cvec_matching || ids.len() > 0 {
-                let mut terms_ids: Vec<_> =
-                    ids.iter().map(|&id| (extract.find_best(id), id)).collect();
-                terms_ids.sort_by_key(|x| x.0 .0);
-                let ((_c1, e1), id1) = terms_ids.remove(0);
-                for ((_c2, e2), id2) in terms_ids {
+        let bucket_eqs: Vec<Vec<Equality<L>>> = by_cvec
+            .par_iter()
+            .map(|ids| self.bucket_candidates(&extract, ids))
+            .collect();
+        let mut new_eqs = EqualityMap::default();
+        for eq in bucket_eqs.into_iter().flatten() {
+            new_eqs.insert(eq.key.clone(), eq);
+        }
+
+        new_eqs.retain(|k, _v| !self.equalities.contains_key(k));
+        (new_eqs, by_cvec.into_iter().cloned().collect())
+    }
+
+    /// The candidates between the e-classes of one cvec bucket.
+    fn bucket_candidates(
+        &self,
+        extract: &Extractor<AstSize, L, SynthAnalysis>,
+        ids: &[Id],
+    ) -> Vec<Equality<L>> {
+        let mut new_eqs = vec![];
+        if self.params.linear_cvec_matching || ids.len() > 0 {
+            let mut terms_ids: Vec<_> =
+                ids.iter().map(|&id| (extract.find_best(id), id)).collect();
+            terms_ids.sort_by_key(|x| x.0 .0);
+            let ((_c1, e1), id1) = terms_ids.remove(0);
+            for ((_c2, e2), id2) in terms_ids {
+                if let Some(mut eq) = Equality::new(&e1, &e2) {
+                    // log::debug!("  Candidate {}", eq);
+                    eq.ids = Some((id1, id2));
+                    new_eqs.push(eq);
+                }
+            }
+        } else {
+            let mut id_iter = ids.iter();
+            while let Some(&id1) = id_iter.next() {
+                let (_, e1) = extract.find_best(id1);
+                for &id2 in id_iter.clone() {
+                    let (_, e2) = extract.find_best(id2);
                     if let Some(mut eq) = Equality::new(&e1, &e2) {
                         // log::debug!("  Candidate {}", eq);
                         eq.ids = Some((id1, id2));
-                        new_eqs.insert(eq.name.clone(), eq);
-                    }
-                }
-            } else {
-                let mut id_iter = ids.iter();
-                while let Some(&id1) = id_iter.next() {
-                    let (_, e1) = extract.find_best(id1);
-                    for &id2 in id_iter.clone() {
-                        let (_, e2) = extract.find_best(id2);
-                        if let Some(mut eq) = Equality::new(&e1, &e2) {
-                            // log::debug!("  Candidate {}", eq);
-                            eq.ids = Some((id1, id2));
-                            new_eqs.insert(eq.name.clone(), eq);
-                        }
+                        new_eqs.push(eq);
                     }
                 }
             }
         }
-
-        new_eqs.retain(|k, _v| !self.equalities.contains_key(k));
-        (new_eqs, by_cvec.into_iter().map(|pair| pair.1).collect())
+        new_eqs
     }
 
     /// Top level function for rule synthesis.
@@ -716,6 +919,18 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
         if self.params.chunk_size == 0 {
             self.params.chunk_size = usize::MAX;
         }
+        if let Some(shard) = self.params.shard {
+            if self.params.abs_timeout > 0 {
+                log::warn!(
+                    "shard {shard} has a timeout, so if it cuts an iteration \
+                     short the shards may not share the last layer"
+                );
+            }
+        }
+
+        let workload = Workload::new(&self.params.workload)
+            .unwrap_or_else(|e| panic!("{}", e));
+        self.open_event_log();
 
         let t = Instant::now();
         assert!(self.params.iters > 0);
@@ -723,11 +938,33 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
             log::info!("[[[ Iteration {} ]]]", iter);
             // cache the iteration that we are on, so that we can restart if necessary
             self.outer_iter = iter;
+            self.event(Event::IterationStart);
 
             let synth_copy = self.clone();
-            let layer =
-                L::make_layer(self.ids().collect_vec(), &synth_copy, iter)
-                    .filter(|n| !n.all(|id| synth_copy.egraph[id].data.exact));
+            let layer: Box<dyn Iterator<Item = LayerTerm<L>> + '_> =
+                match &workload {
+                    Some(workload) => Box::new(workload.layer(
+                        self.ids().collect_vec(),
+                        &synth_copy.egraph,
+                        self.params.workload_hole_depth,
+                    )),
+                    None => Box::new(
+                        L::make_layer(
+                            self.ids().collect_vec(),
+                            &synth_copy,
+                            iter,
+                        )
+                        .filter(|n| {
+                            !n.all(|id| synth_copy.egraph[id].data.exact)
+                        })
+                        .map(LayerTerm::Node),
+                    ),
+                };
+            // a sharded run only enumerates its share of the last layer
+            let layer = match self.params.shard {
+                Some(shard) if iter == self.params.iters => shard.filter(layer),
+                _ => layer,
+            };
 
             // using a filter instead of retain, because make_layer returns an iter
             // and not a vector
@@ -765,12 +1002,32 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
                     self.egraph.total_size(),
                     self.egraph.number_of_classes(),
                 );
-                for node in chunk {
-                    if self.check_time() {
-                        break 'outer;
+                // the cvecs of a chunk are evaluated in batches, so that an
+                // unbounded chunk doesn't hold the whole layer in memory
+                let mut terms = 0;
+                for batch in &chunk.chunks(PRECOMPUTE_BATCH_SIZE) {
+                    let batch: Vec<LayerTerm<L>> = batch.collect();
+                    self.precompute_cvecs(&batch);
+                    for term in batch {
+                        if self.check_time() {
+                            self.clear_precomputed_cvecs();
+                            self.event(Event::Layer { terms });
+                            break 'outer;
+                        }
+                        terms += 1;
+                        match term {
+                            LayerTerm::Node(node) => {
+                                self.egraph.add(node);
+                            }
+                            LayerTerm::Template { template, fill } => {
+                                let workload = workload.as_ref().unwrap();
+                                workload.add(&mut self.egraph, template, &fill);
+                            }
+                        }
                     }
-                    self.egraph.add(node);
+                    self.clear_precomputed_cvecs();
                 }
+                self.event(Event::Layer { terms });
                 'inner: loop {
                     log::info!("Starting inner loop {}", self.inner_iter);
                     // we have reached the inner loop, set inner_restored = true
@@ -795,26 +1052,36 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
                         "cvec matching... (no_conditionals: {})",
                         self.params.no_conditionals
                     );
+                    let buckets = self.intern_cvecs();
                     let (candidates, _) = if self.params.no_conditionals {
-                        self.cvec_match()
+                        self.cvec_match(&buckets)
                     } else {
                         (self.cvec_match_pair_wise(), vec![])
                     };
                     let rule_discovery =
                         rule_discovery_before.elapsed().as_secs_f64();
                     log::info!("{} candidate eqs", candidates.len());
+                    let found = candidates.len();
 
                     // remove any known bad rules from the candiate set
                     let candidates: EqualityMap<L> = candidates
                         .into_iter()
-                        .filter(|eq| !self.poison_rules.contains(&eq.1))
+                        .filter(|(key, _)| !self.rejected.contains_key(key))
                         .collect();
+                    self.event(Event::Candidates {
+                        found,
+                        remaining: candidates.len(),
+                    });
 
                     log::info!(
                         "egraph n={}, e={}",
                         self.egraph.total_size(),
                         self.egraph.number_of_classes(),
                     );
+                    self.event(Event::EGraph {
+                        nodes: self.egraph.total_size(),
+                        classes: self.egraph.number_of_classes(),
+                    });
 
                     let rule_minimize_before = Instant::now();
 
@@ -823,16 +1090,18 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
 
                     let rule_minimize =
                         rule_minimize_before.elapsed().as_secs_f64();
-
-                    for bad in bads {
-                        let s = format!("{}", bad.0);
-                        // make sure that none of the rules contain a quote
-                        if s.contains("!!!") || s.contains("\"") {
-                            log::error!("culprit: {}", bad.0);
-                            panic!();
-                        }
-                        // log::debug!("adding {} to poison set", bad.0);
-                        self.poison_rules.insert(bad.1);
+                    self.event(Event::ChooseEqs {
+                        accepted: eqs.len(),
+                        rejected: bads.len(),
+                    });
+                    self.event(Event::Timing {
+                        run_rewrites,
+                        rule_discovery,
+                        rule_minimization: rule_minimize,
+                    });
+
+                    for (bad, result) in bads {
+                        self.reject(&bad, result);
                     }
 
                     if eqs.is_empty() {
@@ -847,7 +1116,7 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
                     for eq in eqs.values() {
                         log::info!("  {}", eq);
                         if !self.params.no_run_rewrites {
-                            assert!(!self.equalities.contains_key(&eq.name));
+                            assert!(!self.equalities.contains_key(&eq.key));
                             if let Some((i, j)) = eq.ids {
                                 self.egraph.union(i, j);
                             }
@@ -855,6 +1124,7 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
                     }
 
                     self.equalities.extend(eqs);
+                    self.write_partial_rules(t.elapsed().as_secs_f64());
 
                     // TODO check formatting for Learned...
                     log::info!("Time taken in... run_rewrites: {}, rule discovery: {}, rule minimization: {}",
@@ -878,20 +1148,25 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
             }
             // reset the inner loop counter
             self.inner_iter = 0;
-            // reset poison rules (honestly not sure why we need this)
-            self.poison_rules = HashSet::default();
+            self.save_rejected();
         }
 
+        self.save_rejected();
+
         let time = t.elapsed().as_secs_f64();
-        let num_rules = self.equalities.len();
-        let mut eqs: Vec<_> = self
+        // separate the rules that we learned from the ones we inherited
+        let (mut inherited, mut eqs): (Vec<_>, Vec<_>) = self
             .equalities
             .clone()
             .into_iter()
             .map(|(_, eq)| eq)
-            .collect();
+            .partition(|eq| self.inherited.contains(&eq.key));
+        let num_rules = eqs.len();
+        self.event(Event::Done { num_rules });
         eqs.sort_by_key(|eq| eq.score());
         eqs.reverse();
+        inherited.sort_by_key(|eq| eq.score());
+        inherited.reverse();
 
         // final run_rewrites
         if self.params.do_final_run {
@@ -909,12 +1184,28 @@ impl<L: SynthLanguage + Serialize + DeserializeOwned> Synthesizer<L, Init> {
             println!("{}", eq);
         }
         println!("Learned {} rules in {:?}", num_rules, time);
+        if !inherited.is_empty() {
+            println!("Inherited {} rules", inherited.len());
+        }
+        if self.smt_unknown > 0 {
+            println!(
+                "Validation was unknown {} times, for {} distinct rules",
+                self.smt_unknown,
+                self.unknown_rules.len()
+            );
+        }
         Report {
             params: self.params,
             time,
             num_rules,
             eqs,
+            inherited,
             smt_unknown: self.smt_unknown,
+            unknown_rules: self
+                .unknown_rules
+                .into_iter()
+                .map(|(_, eq)| eq)
+                .collect(),
         }
     }
 }
@@ -927,8 +1218,17 @@ pub struct Report<L: SynthLanguage> {
     // pub lang_config: L::Config,
     pub time: f64,
     pub num_rules: usize,
+    /// Number of times that validating a candidate was inconclusive.
     pub smt_unknown: usize,
+    /// Candidates whose validity couldn't be decided. Those that were
+    /// accepted anyway also appear in `eqs`.
+    #[serde(default)]
+    pub unknown_rules: Vec<Equality<L>>,
+    /// Rules learned by this run.
     pub eqs: Vec<Equality<L>>,
+    /// Rules loaded from `SynthParams::prior_rulesets`.
+    #[serde(default)]
+    pub inherited: Vec<Equality<L>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -938,6 +1238,23 @@ struct SlimReport<L: SynthLanguage> {
     eqs: Vec<Equality<L>>,
 }
 
+/// Read the rules of a ruleset written by ruler. This includes the rules a
+/// report inherited from earlier rulesets.
+pub fn read_ruleset<L: SynthLanguage>(
+    path: &Path,
+) -> std::io::Result<Vec<Equality<L>>> {
+    let file = std::fs::File::open(path)?;
+    let data: serde_json::Value = serde_json::from_reader(file)?;
+    let mut eqs = vec![];
+    for key in ["eqs", "inherited"] {
+        if let Some(val) = data.get(key) {
+            let more: Vec<Equality<L>> = serde_json::from_value(val.clone())?;
+            eqs.extend(more);
+        }
+    }
+    Ok(eqs)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SynthParams {
     pub seed: u64,
@@ -960,6 +1277,83 @@ pub struct SynthParams {
     pub do_final_run: bool,
     pub enable_explanations: bool,
     pub enable_checkpointing: bool,
+    /// Directory that checkpoints are written to. Defaults to the current
+    /// directory.
+    #[serde(default)]
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Only keep this many of the most recent checkpoints. Keeps all of
+    /// them if unset.
+    #[serde(default)]
+    pub checkpoint_keep: Option<usize>,
+    /// Rulesets from earlier runs whose rules are trusted and used as the
+    /// starting point for this run.
+    #[serde(default)]
+    pub prior_rulesets: Vec<PathBuf>,
+    /// Term templates to enumerate instead of using
+    /// `SynthLanguage::make_layer`, once per iteration. Pattern variables
+    /// in a template are holes that are filled with e-classes of the
+    /// egraph. Entries like `name := pattern` form a grammar, see the
+    /// `workload` module.
+    #[serde(default)]
+    pub workload: Vec<String>,
+    /// Only fill the holes of workload templates that aren't nonterminals
+    /// with e-classes whose smallest term is at most this deep. Variables
+    /// and constants have depth 1. Every e-class is used if unset.
+    #[serde(default)]
+    pub workload_hole_depth: Option<usize>,
+    /// File that rejected candidates are loaded from at the start of the
+    /// run and saved to after every iteration, so that later runs don't
+    /// validate them again.
+    #[serde(default)]
+    pub rejected_cache: Option<PathBuf>,
+    #[serde(default)]
+    pub choose_eqs: ChooseEqsParams,
+    /// File that progress events are appended to, one JSON object per line.
+    #[serde(default)]
+    pub events_file: Option<PathBuf>,
+    /// Ruleset file that is rewritten every time rules are accepted, so
+    /// that the rules learned so far survive if synthesis is killed.
+    #[serde(default)]
+    pub partial_output: Option<PathBuf>,
+    /// Only enumerate this shard of the terms of the last iteration. See
+    /// [run_shards] and [merge]. The shards of a run should each have their
+    /// own `checkpoint_dir`, `rejected_cache`, `events_file` and
+    /// `partial_output`.
+    #[serde(default)]
+    pub shard: Option<Shard>,
+}
+
+/// Controls how `choose_eqs` selects rules from the candidates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChooseEqsParams {
+    /// How many rules to validate before minimizing the remaining
+    /// candidates again. Each step size is run in order.
+    pub steps: Vec<usize>,
+    /// Steps smaller than `small_step` are skipped when more than `cutoff`
+    /// candidates remain, since they minimize too often to be fast.
+    pub small_step: usize,
+    pub cutoff: usize,
+    /// Indices of the score components to rank candidates by, in order of
+    /// importance. Components from [SynthLanguage::domain_score] come after
+    /// the five components of [SynthLanguage::score]. All components are
+    /// used in order if this is empty.
+    pub score_order: Vec<usize>,
+    /// Weights that each score component is multiplied by. Components
+    /// without a weight have a weight of 1.
+    pub score_weights: Vec<i32>,
+}
+
+impl Default for ChooseEqsParams {
+    fn default() -> Self {
+        Self {
+            steps: vec![100, 10, 1],
+            small_step: 10,
+            cutoff: 200,
+            score_order: vec![],
+            score_weights: vec![],
+        }
+    }
 }
 
 impl Default for SynthParams {
@@ -985,6 +1379,16 @@ impl Default for SynthParams {
             do_final_run: false,
             enable_explanations: false,
             enable_checkpointing: false,
+            checkpoint_dir: None,
+            checkpoint_keep: None,
+            prior_rulesets: vec![],
+            workload: vec![],
+            workload_hole_depth: None,
+            rejected_cache: None,
+            choose_eqs: ChooseEqsParams::default(),
+            events_file: None,
+            partial_output: None,
+            shard: None,
         }
     }
 }
@@ -997,14 +1401,24 @@ impl SynthParams {
         let config = serde_json::from_reader(file)?;
         Ok(config)
     }
+
+    /// Check that every template of [SynthParams::workload] parses as a
+    /// pattern of `L`. [Synthesizer::run] panics on templates that don't.
+    pub fn check_workload<L: SynthLanguage>(&self) -> Result<(), String> {
+        Workload::<L>::new(&self.workload).map(|_| ())
+    }
 }
 
-/// A mapping from a name to an `Equality`.
-pub type EqualityMap<L> = IndexMap<Arc<str>, Equality<L>>;
+/// A mapping from a [RuleKey] to an `Equality`.
+pub type EqualityMap<L> = IndexMap<RuleKey, Equality<L>>;
 
 /// A CVec is a data structure that stores the result of evaluating a term on concrete inputs.
 pub type CVec<L> = Vec<Option<<L as SynthLanguage>::Constant>>;
 
+/// A cvec as it is stored in the egraph. E-classes with the same cvec share
+/// a single allocation, see [Synthesizer::intern_cvecs].
+pub type SharedCVec<L> = Arc<[Option<<L as SynthLanguage>::Constant>]>;
+
 /// Simple macro for generating cvecs for unary, binary, and ternary ops.
 #[macro_export]
 macro_rules! map {
@@ -1044,7 +1458,7 @@ macro_rules! map {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(bound = "L: SynthLanguage")]
 pub struct Signature<L: SynthLanguage> {
-    pub cvec: CVec<L>,
+    pub cvec: SharedCVec<L>,
     pub exact: bool,
     pub vars: Vec<egg::Symbol>,
 }
@@ -1073,11 +1487,14 @@ impl<L: SynthLanguage> egg::Analysis<L> for SynthAnalysis {
         let mut changed_b = false;
 
         if !to.cvec.is_empty() && !from.cvec.is_empty() {
+            // cvecs are shared, so only copy `to` if it has to change
+            let mut cvec: Option<CVec<L>> = None;
             for i in 0..to.cvec.len() {
                 match (to.cvec[i].clone(), from.cvec[i].clone()) {
                     (None, Some(_)) => {
                         changed_a = true;
-                        to.cvec[i] = from.cvec[i].clone();
+                        cvec.get_or_insert_with(|| to.cvec.to_vec())[i] =
+                            from.cvec[i].clone();
                     }
                     (Some(x), Some(y)) => {
                         assert_eq!(x, y, "cvecs do not match at index {}", i)
@@ -1088,6 +1505,9 @@ impl<L: SynthLanguage> egg::Analysis<L> for SynthAnalysis {
                     _ => (),
                 }
             }
+            if let Some(cvec) = cvec {
+                to.cvec = cvec.into();
+            }
 
             to.exact |= from.exact;
         }
@@ -1115,9 +1535,13 @@ impl<L: SynthLanguage> egg::Analysis<L> for SynthAnalysis {
     }
 
     fn make(egraph: &EGraph<L, Self>, enode: &L) -> Self::Data {
-        let get_cvec = |i: &Id| &egraph[*i].data.cvec;
+        let get_cvec = |i: &Id| &*egraph[*i].data.cvec;
+        let cvec = match egraph.analysis.precomputed.get(enode) {
+            Some(cvec) => cvec.clone(),
+            None => enode.eval(egraph.analysis.cvec_len, get_cvec).into(),
+        };
         Signature {
-            cvec: enode.eval(egraph.analysis.cvec_len, get_cvec),
+            cvec,
             exact: !enode.is_var() && enode.all(|i| egraph[i].data.exact),
             vars: enode.to_var().into_iter().collect_vec(),
         }
@@ -1145,10 +1569,10 @@ impl<L: SynthLanguage> Synthesizer<L, Init> {
         mut candidates: EqualityMap<L>,
         step: usize,
         should_validate: bool,
-    ) -> (EqualityMap<L>, EqualityMap<L>) {
+    ) -> (EqualityMap<L>, Vec<(Equality<L>, ValidationResult)>) {
         log::info!("Entering shrink: should_validate: {should_validate}");
         let mut keepers = EqualityMap::default();
-        let mut bads = EqualityMap::default();
+        let mut bads = vec![];
         let initial_len = candidates.len();
         'outer: while !candidates.is_empty() {
             if self.check_time() {
@@ -1156,39 +1580,93 @@ impl<L: SynthLanguage> Synthesizer<L, Init> {
             }
 
             // best are last
-            candidates.sort_by(|_, eq1, _, eq2| eq1.score().cmp(&eq2.score()));
+            let scores: HashMap<RuleKey, Vec<i32>> = candidates
+                .iter()
+                .map(|(name, eq)| (name.clone(), self.candidate_score(eq)))
+                .collect();
+            candidates.sort_by(|name1, _, name2, _| {
+                scores[name1].cmp(&scores[name2])
+            });
 
             // take `step` valid rules from the end of new_eqs
             let mut took = 0;
-            while let Some((name, eq)) = candidates.pop() {
+            while took < step && !candidates.is_empty() {
                 if self.check_time() {
                     break 'outer;
                 }
 
-                // Call `L::is_valid` on `eq`. If valid, add to `keepers`
-                if should_validate {
+                // pop the best candidates that could still fill this step
+                let batch: Vec<(RuleKey, Equality<L>)> =
+                    (0..step - took).map_while(|_| candidates.pop()).collect();
+
+                // Call `L::is_valid` on every equation of the batch in
+                // parallel. Each candidate gets its own rng, seeded in
+                // order from `self.rng`, so the results don't depend on
+                // how the work is scheduled.
+                let valid: Vec<(ValidationResult, f64)> = if should_validate {
+                    let seeds: Vec<u64> =
+                        batch.iter().map(|_| self.rng.gen()).collect();
+                    let synth: &Self = self;
                     let rule_validation = Instant::now();
-                    let valid = L::is_valid(self, &eq.lhs, &eq.rhs);
+                    let valid = batch
+                        .par_iter()
+                        .zip(seeds)
+                        .map(|((_, eq), seed)| {
+                            let start = Instant::now();
+                            let mut rng = Pcg32::seed_from_u64(seed);
+                            let valid = match &eq.guard {
+                                Some(guard) => L::is_valid_guarded(
+                                    synth, &mut rng, &eq.lhs, &eq.rhs, guard,
+                                ),
+                                None => L::is_valid(
+                                    synth, &mut rng, &eq.lhs, &eq.rhs,
+                                ),
+                            };
+                            (valid, start.elapsed().as_secs_f64())
+                        })
+                        .collect();
                     log::debug!(
-                        "Time taken in validation: {}",
+                        "Time taken in validating {} candidates: {}",
+                        batch.len(),
                         rule_validation.elapsed().as_secs_f64()
                     );
-
-                    if valid {
-                        log::debug!("inserting {}", eq);
-                        let old = keepers.insert(name, eq);
-                        took += old.is_none() as usize;
-                    } else {
-                        bads.insert(name, eq);
-                    }
+                    valid
                 } else {
-                    let old = keepers.insert(name, eq);
-                    took += old.is_none() as usize;
-                }
+                    let unvalidated =
+                        ValidationResult::Valid(Validation::Unvalidated);
+                    vec![(unvalidated, 0.0); batch.len()]
+                };
 
-                // break if we have taken more than our step size
-                if took >= step {
-                    break;
+                // If valid, add to `keepers`
+                for ((name, mut eq), (valid, time)) in
+                    batch.into_iter().zip(valid)
+                {
+                    if let ValidationResult::Unknown(_) = valid {
+                        log::debug!("validation of {} is unknown", eq);
+                        self.smt_unknown += 1;
+                        self.unknown_rules.insert(name.clone(), eq.clone());
+                    }
+                    let accepted = match valid {
+                        ValidationResult::Valid(validation)
+                        | ValidationResult::Unknown(Some(validation)) => {
+                            Ok(validation)
+                        }
+                        rejected => Err(rejected),
+                    };
+                    match accepted {
+                        Ok(validation) => {
+                            log::debug!("inserting {}", eq);
+                            eq.learned(
+                                self.outer_iter,
+                                self.inner_iter,
+                                validation,
+                                time,
+                            );
+                            let old = keepers.insert(name, eq);
+                            took += old.is_none() as usize;
+                        }
+                        Err(result) => bads.push((eq, result)),
+                    }
                 }
             }
 
@@ -1238,12 +1716,26 @@ impl<L: SynthLanguage> Synthesizer<L, Init> {
             // }
             // let mut runner = self.mk_runner(egraph);
 
+            // conditional candidates don't hold for every term in the
+            // egraph, so they aren't used to minimize the others. They are
+            // only dropped when the rules we know about already derive them.
+            let guarded: Vec<(RuleKey, Equality<L>)> = candidates
+                .iter()
+                .filter(|(_, eq)| eq.guard.is_some())
+                .map(|(name, eq)| (name.clone(), eq.clone()))
+                .collect();
+            candidates.retain(|_, eq| eq.guard.is_none());
+
             let mut runner = self.mk_runner(self.initial_egraph.clone());
             // add all candidates to the egraph
             for candidate_eq in candidates.values() {
                 runner = runner.with_expr(&L::instantiate(&candidate_eq.lhs));
                 runner = runner.with_expr(&L::instantiate(&candidate_eq.rhs));
             }
+            for (_, guarded_eq) in &guarded {
+                runner = runner.with_expr(&L::instantiate(&guarded_eq.lhs));
+                runner = runner.with_expr(&L::instantiate(&guarded_eq.rhs));
+            }
 
             // perform the rewriting
             runner = runner.run(rewrites);
@@ -1254,19 +1746,29 @@ impl<L: SynthLanguage> Synthesizer<L, Init> {
             );
 
             // extract the smallest rules
-            let old_len = candidates.len();
+            let old_len = candidates.len() + guarded.len();
             let extract = Extractor::new(&runner.egraph, AstSize);
             candidates.clear();
+            let mut roots = runner.roots.clone();
+            let guarded_roots =
+                roots.split_off(roots.len() - 2 * guarded.len());
+            for ((id0, id1), (key, eq)) in
+                guarded_roots.into_iter().tuples().zip(guarded)
+            {
+                if runner.egraph.find(id0) != runner.egraph.find(id1) {
+                    candidates.insert(key, eq);
+                }
+            }
             // for ((id0, _expr0), (id1, _expr1)) in
             // for (id0, id1) in runner.roots.into_iter().tuples() {
-            for (id0, id1) in runner.roots.into_iter().tuples() {
+            for (id0, id1) in roots.into_iter().tuples() {
                 // if our roots are in different eclasses
                 if runner.egraph.find(id0) != runner.egraph.find(id1) {
                     let left = extract.find_best(id0).1;
                     let right = extract.find_best(id1).1;
                     if let Some(eq) = Equality::new(&left, &right) {
-                        if !self.equalities.contains_key(&eq.name) {
-                            candidates.insert(eq.name.clone(), eq);
+                        if !self.equalities.contains_key(&eq.key) {
+                            candidates.insert(eq.key.clone(), eq);
                         }
                     }
                 }
@@ -1289,20 +1791,70 @@ impl<L: SynthLanguage> Synthesizer<L, Init> {
         (keepers, bads)
     }
 
+    /// Write the rules learned so far to `SynthParams::partial_output`, in
+    /// the same format as the rules of a [Report].
+    fn write_partial_rules(&self, time: f64) {
+        if let Some(path) = &self.params.partial_output {
+            let (inherited, eqs): (Vec<_>, Vec<_>) = self
+                .equalities
+                .values()
+                .partition(|eq| self.inherited.contains(&eq.key));
+            let partial = serde_json::json!({
+                "time": time,
+                "eqs": eqs,
+                "inherited": inherited,
+            });
+            match write_json_atomic(path, &partial) {
+                Ok(()) => log::info!(
+                    "Wrote {} rules learned so far to {path:?}",
+                    eqs.len()
+                ),
+                Err(e) => {
+                    log::warn!("Failed to write partial rules to {path:?}: {e}")
+                }
+            }
+        }
+    }
+
+    /// The score that `choose_eqs` ranks candidates by. This is
+    /// [SynthLanguage::score] followed by [SynthLanguage::domain_score],
+    /// with the components weighted and ordered according to
+    /// [ChooseEqsParams].
+    fn candidate_score(&self, eq: &Equality<L>) -> Vec<i32> {
+        let mut components = L::score(&eq.lhs, &eq.rhs).to_vec();
+        components.extend(L::domain_score(self, &eq.lhs, &eq.rhs));
+
+        let params = &self.params.choose_eqs;
+        let weighted = |i: usize| {
+            let weight = params.score_weights.get(i).copied().unwrap_or(1);
+            components.get(i).map(|c| c * weight)
+        };
+        if params.score_order.is_empty() {
+            (0..components.len()).filter_map(weighted).collect()
+        } else {
+            params
+                .score_order
+                .iter()
+                .filter_map(|i| weighted(*i))
+                .collect()
+        }
+    }
+
     /// Apply rewrites rules as they are being inferred, to minimize the candidate space.
     #[inline(never)]
     fn choose_eqs(
         &mut self,
         mut new_eqs: EqualityMap<L>,
-    ) -> (EqualityMap<L>, EqualityMap<L>) {
-        let mut bads = EqualityMap::default();
+    ) -> (EqualityMap<L>, Vec<(Equality<L>, ValidationResult)>) {
+        let mut bads = vec![];
         let /* mut */ should_validate = true;
-        for step in vec![100, 10, 1] {
+        let params = self.params.choose_eqs.clone();
+        for step in params.steps {
             if self.params.rules_to_take < step {
                 continue;
             }
             let n_rules = usize::min(self.params.rules_to_take, new_eqs.len());
-            if step < 10 && n_rules > 200 {
+            if step < params.small_step && n_rules > params.cutoff {
                 break;
             }
             let (n, b) = self.shrink(new_eqs, step, should_validate);
@@ -0,0 +1,169 @@
+use crate::*;
+use std::fs::File;
+
+/// Parameters for minimizing a ruleset.
+pub struct MinimizeParams {
+    /// The ruleset to minimize.
+    pub input: String,
+    /// Where to write the minimized ruleset.
+    pub out: String,
+    /// Where to write the report of removed rules.
+    pub report: String,
+    /// Limits for the equality saturation used to derive each rule.
+    pub iter_limit: usize,
+    pub node_limit: usize,
+    pub time_limit: u64,
+}
+
+/// The result of minimizing a ruleset.
+pub struct Minimized<L: SynthLanguage> {
+    /// Rules that can't be derived from the other kept rules.
+    pub kept: Vec<Equality<L>>,
+    /// Removed rules, each with the names of the rules used in its proof.
+    pub removed: Vec<(Equality<L>, Vec<Arc<str>>)>,
+}
+
+/// Minimize the ruleset `params.input`, writing the minimized ruleset to
+/// `params.out` and a report of the removed rules to `params.report`.
+pub fn minimize<L: SynthLanguage>(
+    params: MinimizeParams,
+) -> std::io::Result<()> {
+    let start = Instant::now();
+    let eqs = read_ruleset::<L>(Path::new(&params.input))?;
+    let n_input = eqs.len();
+    let Minimized { kept, removed } = minimize_eqs(eqs, &[], &params);
+    let n_kept = kept.len();
+    let time = start.elapsed().as_secs_f64();
+    println!("Kept {} of {} rules in {}s", n_kept, n_input, time);
+
+    let out = File::create(&params.out)?;
+    serde_json::to_writer_pretty(out, &SlimReport { time, eqs: kept })?;
+
+    let removed: Vec<_> = removed
+        .into_iter()
+        .map(|(eq, derived_by)| {
+            serde_json::json!({
+                "rule": eq,
+                "derived_by": derived_by,
+            })
+        })
+        .collect();
+    let report = serde_json::json!({
+        "input": params.input,
+        "input_rules": n_input,
+        "kept_rules": n_kept,
+        "time": time,
+        "removed": removed,
+    });
+    let file = File::create(&params.report)?;
+    serde_json::to_writer_pretty(file, &report)?;
+    Ok(())
+}
+
+/// Greedily remove every rule in `eqs` that can be derived from the rules
+/// that remain and the rules in `fixed`. The `fixed` rules are never
+/// removed. Rules with the lowest score are tried first, so that the most
+/// general rules are the ones that are kept.
+pub fn minimize_eqs<L: SynthLanguage>(
+    mut eqs: Vec<Equality<L>>,
+    fixed: &[Equality<L>],
+    params: &MinimizeParams,
+) -> Minimized<L> {
+    eqs.sort_by_key(|eq| eq.score());
+    let mut kept: EqualityMap<L> =
+        eqs.into_iter().map(|eq| (eq.key.clone(), eq)).collect();
+    let keys: Vec<RuleKey> = kept.keys().cloned().collect();
+
+    let mut removed = vec![];
+    for (i, key) in keys.iter().enumerate() {
+        let eq = kept.shift_remove(key).unwrap();
+        match derivation(&kept, fixed, &eq, params) {
+            Some(derived_by) => {
+                log::debug!("{} is derivable from {:?}", eq, derived_by);
+                removed.push((eq, derived_by));
+            }
+            None => {
+                kept.insert(key.clone(), eq);
+            }
+        }
+        log::debug!(
+            "Checked {}/{} rules, removed {}.",
+            i + 1,
+            keys.len(),
+            removed.len()
+        );
+    }
+    log::info!("Checked {} rules, removed {}.", keys.len(), removed.len());
+
+    let mut kept: Vec<_> = kept.into_iter().map(|(_, eq)| eq).collect();
+    kept.sort_by_key(|eq| eq.score());
+    kept.reverse();
+    Minimized { kept, removed }
+}
+
+/// Try to derive `eq` using `rules` and `fixed`. If we can, return the names
+/// of the rules that are used in the proof.
+fn derivation<L: SynthLanguage>(
+    rules: &EqualityMap<L>,
+    fixed: &[Equality<L>],
+    eq: &Equality<L>,
+    params: &MinimizeParams,
+) -> Option<Vec<Arc<str>>> {
+    let lhs = L::instantiate(&eq.lhs);
+    let rhs = L::instantiate(&eq.rhs);
+    let mut runner: Runner<L, SynthAnalysis, ()> = Runner::default()
+        .with_explanations_enabled()
+        .with_expr(&lhs)
+        .with_expr(&rhs)
+        .with_iter_limit(params.iter_limit)
+        .with_node_limit(params.node_limit)
+        .with_time_limit(Duration::from_secs(params.time_limit))
+        .with_scheduler(SimpleScheduler)
+        .with_hook(|r| {
+            if r.egraph.find(r.roots[0]) == r.egraph.find(r.roots[1]) {
+                Err("Done".to_string())
+            } else {
+                Ok(())
+            }
+        })
+        .run(rules.values().chain(fixed).flat_map(|eq| &eq.rewrites));
+
+    if runner.egraph.find(runner.roots[0])
+        != runner.egraph.find(runner.roots[1])
+    {
+        return None;
+    }
+
+    // map the names of the rewrites in the proof back to their equalities.
+    // rules that fired without contributing to the proof are left out.
+    let owners: HashMap<Symbol, &Arc<str>> = rules
+        .values()
+        .chain(fixed)
+        .flat_map(|eq| eq.rewrites.iter().map(move |rw| (rw.name, &eq.name)))
+        .collect();
+    let mut explanation = runner.explain_equivalence(&lhs, &rhs);
+    let mut used = vec![];
+    for term in explanation.make_flat_explanation() {
+        proof_rules(term, &mut used);
+    }
+    let mut derived_by: Vec<Arc<str>> = used
+        .iter()
+        .filter_map(|rw| owners.get(rw).map(|name| (*name).clone()))
+        .collect();
+    derived_by.sort();
+    derived_by.dedup();
+    Some(derived_by)
+}
+
+/// Collect the names of the rewrites applied anywhere in `term`, including
+/// the rewrites applied to its children.
+fn proof_rules<L: SynthLanguage>(
+    term: &egg::FlatTerm<L>,
+    out: &mut Vec<Symbol>,
+) {
+    out.extend(term.backward_rule);
+    out.extend(term.forward_rule);
+    for child in &term.children {
+        proof_rules(child, out);
+    }
+}
@@ -0,0 +1,181 @@
+//! Parallel cvec evaluation, matching and interning.
+//!
+//! Adding a node to the egraph evaluates its cvec in the `make` of
+//! [SynthAnalysis], one node at a time. Before a batch of a layer is added,
+//! the cvecs of its nodes are evaluated in parallel and cached in the
+//! analysis, where `make` picks them up. A cvec only depends on the cvecs of
+//! the node's children, so this gives the same result as evaluating the
+//! nodes one at a time.
+use std::{
+    any::Any,
+    hash::{Hash, Hasher},
+};
+
+use crate::*;
+
+/// How many terms of a layer [Synthesizer::precompute_cvecs] is called with
+/// at once, which bounds the memory used by the precomputed cvecs.
+pub(crate) const PRECOMPUTE_BATCH_SIZE: usize = 100_000;
+
+/// Cvecs of nodes that are about to be added to the egraph, keyed by the
+/// canonical node. [SynthAnalysis] isn't generic over the language, so the
+/// map is stored type erased.
+#[derive(Clone, Default)]
+pub(crate) struct PrecomputedCVecs(Option<Arc<dyn Any + Send + Sync>>);
+
+impl Debug for PrecomputedCVecs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PrecomputedCVecs")
+    }
+}
+
+impl PrecomputedCVecs {
+    pub(crate) fn get<L: SynthLanguage>(
+        &self,
+        node: &L,
+    ) -> Option<&SharedCVec<L>> {
+        self.0
+            .as_ref()?
+            .downcast_ref::<HashMap<L, SharedCVec<L>>>()?
+            .get(node)
+    }
+}
+
+impl<L: SynthLanguage> Synthesizer<L, Init> {
+    /// Evaluate the cvecs of the nodes in `terms` in parallel, so that adding
+    /// them to the egraph doesn't have to. Workload templates are evaluated
+    /// when they are added, as before. The cache has to be cleared with
+    /// [Self::clear_precomputed_cvecs] once the terms have been added,
+    /// before the egraph changes in any other way.
+    pub(crate) fn precompute_cvecs(&mut self, terms: &[LayerTerm<L>]) {
+        let egraph = &self.egraph;
+        let cvec_len = egraph.analysis.cvec_len;
+        let cvecs: HashMap<L, SharedCVec<L>> = terms
+            .par_iter()
+            .filter_map(|term| match term {
+                LayerTerm::Node(node) => Some(node),
+                LayerTerm::Template { .. } => None,
+            })
+            .map(|node| {
+                let node = node.clone().map_children(|id| egraph.find(id));
+                let cvec = node.eval(cvec_len, |id| &*egraph[*id].data.cvec);
+                (node, cvec.into())
+            })
+            .collect();
+        self.egraph.analysis.precomputed =
+            PrecomputedCVecs(Some(Arc::new(cvecs)));
+    }
+
+    pub(crate) fn clear_precomputed_cvecs(&mut self) {
+        self.egraph.analysis.precomputed = PrecomputedCVecs::default();
+    }
+
+    /// Make the e-classes with equal cvecs share one allocation, and return
+    /// the classes bucketed by their cvecs. Adding nodes and merging classes
+    /// allocates new cvecs, so this is called once per inner iteration, and
+    /// [Synthesizer::cvec_match] reuses the buckets instead of hashing the
+    /// cvecs again.
+    pub(crate) fn intern_cvecs(&mut self) -> Vec<Vec<Id>> {
+        let ids: Vec<Id> = self.ids().collect();
+        let buckets: Vec<Vec<Id>> = bucket_by_cvec(&self.egraph, &ids)
+            .into_iter()
+            .map(|(_, ids)| ids)
+            .collect();
+        log::info!(
+            "interned {} cvecs into {} unique cvecs",
+            ids.len(),
+            buckets.len()
+        );
+        for bucket in &buckets {
+            let shared = self.egraph[bucket[0]].data.cvec.clone();
+            for id in &bucket[1..] {
+                self.egraph[*id].data.cvec = shared.clone();
+            }
+        }
+        buckets
+    }
+}
+
+/// Group `ids` by their cvecs, see [bucket_by_key].
+pub(crate) fn bucket_by_cvec<'a, L: SynthLanguage>(
+    egraph: &'a EGraph<L, SynthAnalysis>,
+    ids: &[Id],
+) -> IndexMap<&'a [Option<L::Constant>], Vec<Id>> {
+    bucket_by_key(ids, |id| &*egraph[id].data.cvec)
+}
+
+/// Group `items` by their keys. The keys are hashed in parallel and split
+/// into one shard per thread, and each shard is grouped in parallel. The
+/// buckets, and the items in each bucket, are in the order of `items`,
+/// exactly as if they had been inserted into the map one at a time.
+fn bucket_by_key<'a, T, K, F>(items: &[T], key: F) -> IndexMap<&'a K, Vec<T>>
+where
+    T: Copy + Send + Sync,
+    K: Hash + Eq + Sync + ?Sized + 'a,
+    F: Fn(T) -> &'a K + Sync,
+{
+    let shards = rayon::current_num_threads();
+    let hashes: Vec<usize> = items
+        .par_iter()
+        .map(|item| {
+            let mut hasher = rustc_hash::FxHasher::default();
+            key(*item).hash(&mut hasher);
+            hasher.finish() as usize
+        })
+        .collect();
+
+    // equal keys have equal hashes, so every bucket is in a single shard.
+    // remember where each bucket first appeared to put them back in order.
+    let mut buckets: Vec<_> = (0..shards)
+        .into_par_iter()
+        .flat_map_iter(|shard| {
+            let mut by_key: IndexMap<_, (usize, Vec<T>)> = IndexMap::default();
+            for (i, item) in items.iter().enumerate() {
+                if hashes[i] % shards == shard {
+                    by_key
+                        .entry(key(*item))
+                        .or_insert_with(|| (i, vec![]))
+                        .1
+                        .push(*item);
+                }
+            }
+            by_key
+                .into_iter()
+                .map(|(k, (first, items))| (first, k, items))
+        })
+        .collect();
+    buckets.sort_unstable_by_key(|(first, _, _)| *first);
+
+    buckets
+        .into_iter()
+        .map(|(_, k, items)| (k, items))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_by_key_is_sequential() {
+        let mut rng = Pcg32::seed_from_u64(0);
+        let keys: Vec<u32> = (0..5000).map(|_| rng.gen_range(0, 50)).collect();
+        let items: Vec<usize> = (0..keys.len()).collect();
+
+        // group the items one at a time
+        let mut sequential: IndexMap<&u32, Vec<usize>> = IndexMap::default();
+        for &i in &items {
+            sequential.entry(&keys[i]).or_default().push(i);
+        }
+        let sequential = sequential.into_iter().collect_vec();
+
+        for threads in [1, 2, 7] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let parallel = pool.install(|| bucket_by_key(&items, |i| &keys[i]));
+            assert_eq!(parallel.into_iter().collect_vec(), sequential);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! A persistent cache of candidate rules that failed validation.
+//!
+//! Rejected rules are keyed by the [RuleKey] of their [Equality], so a rule
+//! is recognized no matter how its variables are named or which way around
+//! it is written. Only rules that were shown to be invalid are written to
+//! disk. Rules whose validation was inconclusive, for example because the
+//! solver timed out, may be accepted by a run with a different validation
+//! config, so they are only remembered for the current run. The cache is
+//! kept for the whole run, saved in checkpoints, and can be written to disk
+//! and loaded by later runs so that rules are only ever disproven once. The
+//! file is versioned like checkpoints are.
+use std::{fs, io};
+
+use crate::*;
+
+/// The version of the rejected rule file layout.
+/// Version history:
+/// 1. the rejections are wrapped in a header and keyed by [RuleKey]
+const REJECTED_VERSION: u32 = 1;
+
+/// The layout of the rejected rule file.
+#[derive(Serialize, Deserialize)]
+struct RejectedFile<R> {
+    version: u32,
+    rejected: Vec<R>,
+}
+
+/// A rule that failed validation, and why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rejection {
+    pub rule: RuleKey,
+    pub lhs: String,
+    pub rhs: String,
+    /// The counterexample or other reason that the rule was rejected.
+    pub reason: String,
+    /// Validation couldn't decide whether the rule is valid.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub inconclusive: bool,
+}
+
+/// Read the rejected rules written by an earlier run.
+pub fn read_rejected(path: &Path) -> io::Result<Vec<Rejection>> {
+    let file = fs::File::open(path)?;
+    let file: RejectedFile<Rejection> =
+        serde_json::from_reader(io::BufReader::new(file))?;
+    if file.version != REJECTED_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "rejected rule file has version {}, expected {}",
+                file.version, REJECTED_VERSION
+            ),
+        ));
+    }
+    Ok(file.rejected)
+}
+
+/// Write rejected rules so that [read_rejected] can load them.
+pub fn write_rejected(path: &Path, rejected: &[&Rejection]) -> io::Result<()> {
+    write_json_atomic(
+        path,
+        &RejectedFile {
+            version: REJECTED_VERSION,
+            rejected: rejected.to_vec(),
+        },
+    )
+}
+
+impl<L: SynthLanguage> Synthesizer<L, Uninit> {
+    /// Load the rejected rules from `SynthParams::rejected_cache`, if that
+    /// file exists.
+    pub(crate) fn load_rejected(&mut self) {
+        let path = match &self.params.rejected_cache {
+            Some(path) if path.exists() => path.clone(),
+            _ => return,
+        };
+        match read_rejected(&path) {
+            Ok(rejected) => {
+                log::info!(
+                    "Loaded {} rejected rules from {path:?}",
+                    rejected.len()
+                );
+                self.rejected
+                    .extend(rejected.into_iter().map(|r| (r.rule.clone(), r)));
+            }
+            Err(e) => {
+                log::warn!("Failed to load rejected rules from {path:?}: {e}")
+            }
+        }
+    }
+}
+
+impl<L: SynthLanguage> Synthesizer<L, Init> {
+    /// Remember that `eq` wasn't accepted by validation, with the `result`
+    /// of validating it, so that it isn't validated again.
+    pub(crate) fn reject(
+        &mut self,
+        eq: &Equality<L>,
+        result: ValidationResult,
+    ) {
+        let (reason, inconclusive) = match result {
+            ValidationResult::Invalid(reason) => (reason, false),
+            _ => ("validation was inconclusive".to_string(), true),
+        };
+        log::debug!("rejecting {eq}: {reason}");
+        self.rejected.insert(
+            eq.key.clone(),
+            Rejection {
+                rule: eq.key.clone(),
+                lhs: eq.lhs.to_string(),
+                rhs: eq.rhs.to_string(),
+                reason,
+                inconclusive,
+            },
+        );
+    }
+
+    /// Write the rules that were shown to be invalid to
+    /// `SynthParams::rejected_cache`, if it is set.
+    pub(crate) fn save_rejected(&self) {
+        if let Some(path) = &self.params.rejected_cache {
+            let rejected: Vec<&Rejection> =
+                self.rejected.values().filter(|r| !r.inconclusive).collect();
+            match write_rejected(path, &rejected) {
+                Ok(()) => log::info!(
+                    "Wrote {} rejected rules to {path:?}",
+                    rejected.len()
+                ),
+                Err(e) => {
+                    log::warn!(
+                        "Failed to write rejected rules to {path:?}: {e}"
+                    )
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejection(rule: &str, lhs: &str, rhs: &str) -> Rejection {
+        Rejection {
+            rule: serde_json::from_value(serde_json::json!(rule)).unwrap(),
+            lhs: lhs.into(),
+            rhs: rhs.into(),
+            reason: "x = 1".into(),
+            inconclusive: false,
+        }
+    }
+
+    #[test]
+    fn test_rejected_file() {
+        let path = std::env::temp_dir()
+            .join(format!("ruler-rejected-{}.json", std::process::id()));
+
+        // the current layout round trips
+        let r = rejection("(+ ?0 ?1) = (+ ?1 ?0)", "(+ ?a ?b)", "(+ ?b ?a)");
+        write_rejected(&path, &[&r]).unwrap();
+        let read = read_rejected(&path).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].rule, r.rule);
+        assert_eq!(read[0].reason, r.reason);
+
+        // other versions aren't loaded
+        let newer = RejectedFile {
+            version: REJECTED_VERSION + 1,
+            rejected: vec![&r],
+        };
+        write_json_atomic(&path, &newer).unwrap();
+        let err = read_rejected(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
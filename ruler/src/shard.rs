@@ -0,0 +1,252 @@
+//! Split synthesis across several worker processes on one machine.
+//!
+//! Every worker runs the same configuration with a different [Shard]. The
+//! iterations before the last one are repeated by every worker, which is
+//! deterministic for a given seed, so all of them start the last iteration
+//! with the same egraph. The terms of the last layer are then dealt out
+//! round robin, so that each worker only enumerates and matches its share.
+//! The partial rulesets of the workers are combined with [merge].
+//!
+//! That only holds if the earlier iterations run to completion. When
+//! `abs_timeout` or `eqsat_time_limit` cut an iteration short, where that
+//! happens depends on how fast each worker is, so the workers can start the
+//! last iteration with different egraphs. The merged rules are still valid,
+//! but the shards may then overlap or miss some of the last layer. A shard
+//! warns about this when it has an `abs_timeout`.
+use std::{fmt, fs, io, process::Command, str::FromStr};
+
+use crate::*;
+
+/// One of `count` shards, written as `index/count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("expected a shard like '0/4', got '{s}'");
+        let (index, count) = s.split_once('/').ok_or_else(err)?;
+        let index = index.trim().parse().map_err(|_| err())?;
+        let count = count.trim().parse().map_err(|_| err())?;
+        if index >= count {
+            return Err(format!("shard {index} is out of range for {count}"));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl Shard {
+    /// Every shard of `count`.
+    pub fn all(count: usize) -> impl Iterator<Item = Shard> {
+        (0..count).map(move |index| Shard { index, count })
+    }
+
+    /// Only keep the items of `iter` that belong to this shard.
+    pub(crate) fn filter<'a, T: 'a>(
+        self,
+        iter: Box<dyn Iterator<Item = T> + 'a>,
+    ) -> Box<dyn Iterator<Item = T> + 'a> {
+        Box::new(
+            iter.enumerate()
+                .filter(move |(i, _)| i % self.count == self.index)
+                .map(|(_, t)| t),
+        )
+    }
+}
+
+/// Run one worker process per shard and wait for all of them. `command`
+/// builds the command line of the worker for a shard.
+pub fn run_shards(
+    count: usize,
+    mut command: impl FnMut(Shard) -> Command,
+) -> io::Result<()> {
+    let workers = Shard::all(count)
+        .map(|shard| {
+            let mut cmd = command(shard);
+            log::info!("starting shard {shard}: {cmd:?}");
+            Ok((shard, cmd.spawn()?))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut failed = vec![];
+    for (shard, mut child) in workers {
+        let status = child.wait()?;
+        log::info!("shard {shard} finished with {status}");
+        if !status.success() {
+            failed.push(shard.to_string());
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("shards {} failed", failed.join(", ")),
+        ))
+    }
+}
+
+/// Parameters for merging the reports of sharded runs.
+pub struct MergeParams {
+    /// The reports written by the workers.
+    pub inputs: Vec<PathBuf>,
+    /// The rejected rule caches written by the workers. Caches that don't
+    /// exist are skipped.
+    pub rejected: Vec<PathBuf>,
+    /// Where to write the union of the `rejected` caches.
+    pub rejected_output: Option<PathBuf>,
+    /// Remove the learned rules that can be derived from the other rules of
+    /// the merged ruleset.
+    pub minimize: bool,
+    /// Limits for the equality saturation used to derive each rule.
+    pub iter_limit: usize,
+    pub node_limit: usize,
+    pub time_limit: u64,
+}
+
+/// Combine the reports of sharded runs into a single report. Rules are
+/// deduplicated by their [RuleKey], and rules learned by one shard that are inherited
+/// by another are kept as learned. Since the workers ran at the same time,
+/// the time of the merged report is the longest of the workers plus the
+/// time spent merging. The rejected rule caches of the workers are combined
+/// into `rejected_output`.
+pub fn merge<L: SynthLanguage>(params: &MergeParams) -> io::Result<Report<L>> {
+    let start = Instant::now();
+    let mut reports: Vec<Report<L>> = vec![];
+    for path in &params.inputs {
+        let file = fs::File::open(path)?;
+        let report = serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{path:?}: {e}"),
+                )
+            })?;
+        reports.push(report);
+    }
+    let first = match reports.first() {
+        Some(report) => report,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no reports to merge",
+            ))
+        }
+    };
+    let mut params_out = first.params.clone();
+    params_out.shard = None;
+
+    let mut eqs: EqualityMap<L> = IndexMap::default();
+    let mut inherited: EqualityMap<L> = IndexMap::default();
+    let mut unknown_rules: EqualityMap<L> = IndexMap::default();
+    let mut time: f64 = 0.0;
+    for report in reports {
+        time = time.max(report.time);
+        for eq in report.eqs {
+            eqs.entry(eq.key.clone()).or_insert(eq);
+        }
+        for eq in report.inherited {
+            inherited.entry(eq.key.clone()).or_insert(eq);
+        }
+        for eq in report.unknown_rules {
+            unknown_rules.entry(eq.key.clone()).or_insert(eq);
+        }
+    }
+    inherited.retain(|key, _| !eqs.contains_key(key));
+
+    if let Some(output) = &params.rejected_output {
+        merge_rejected(&params.rejected, output)?;
+    }
+    log::info!(
+        "merged {} learned and {} inherited rules from {} reports",
+        eqs.len(),
+        inherited.len(),
+        params.inputs.len()
+    );
+
+    let mut eqs: Vec<Equality<L>> = eqs.into_iter().map(|(_, eq)| eq).collect();
+    if params.minimize {
+        // only the limits are used by `minimize_eqs`
+        let minimize_params = MinimizeParams {
+            input: String::new(),
+            out: String::new(),
+            report: String::new(),
+            iter_limit: params.iter_limit,
+            node_limit: params.node_limit,
+            time_limit: params.time_limit,
+        };
+        // inherited rules can derive learned rules, but are never removed
+        let fixed: Vec<Equality<L>> = inherited.values().cloned().collect();
+        let n_merged = eqs.len();
+        eqs = minimize_eqs(eqs, &fixed, &minimize_params).kept;
+        log::info!("minimized {} merged rules to {}", n_merged, eqs.len());
+    }
+
+    Ok(Report {
+        params: params_out,
+        time: time + start.elapsed().as_secs_f64(),
+        num_rules: eqs.len(),
+        // shards may have failed to decide the same candidate
+        smt_unknown: unknown_rules.len(),
+        unknown_rules: unknown_rules.into_iter().map(|(_, eq)| eq).collect(),
+        eqs,
+        inherited: inherited.into_iter().map(|(_, eq)| eq).collect(),
+    })
+}
+
+/// Write the union of the rejected rule caches at `inputs` to `output`.
+fn merge_rejected(inputs: &[PathBuf], output: &Path) -> io::Result<()> {
+    let mut rejected: IndexMap<RuleKey, Rejection> = IndexMap::default();
+    for path in inputs {
+        if !path.exists() {
+            log::warn!("no rejected rules at {path:?}");
+            continue;
+        }
+        for r in read_rejected(path)? {
+            rejected.entry(r.rule.clone()).or_insert(r);
+        }
+    }
+    log::info!(
+        "merged {} rejected rules from {} caches",
+        rejected.len(),
+        inputs.len()
+    );
+    write_rejected(output, &rejected.values().collect_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_from_str() {
+        assert_eq!("1/4".parse(), Ok(Shard { index: 1, count: 4 }));
+        assert_eq!(" 0 / 2 ".parse(), Ok(Shard { index: 0, count: 2 }));
+        assert_eq!(Shard { index: 3, count: 8 }.to_string(), "3/8");
+        assert!("4/4".parse::<Shard>().is_err());
+        assert!("1/0".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+        assert!("a/b".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn test_shard_filter() {
+        let shards = Shard::all(3)
+            .map(|shard| shard.filter(Box::new(0..10)).collect_vec())
+            .collect_vec();
+        assert_eq!(
+            shards,
+            vec![vec![0, 3, 6, 9], vec![1, 4, 7], vec![2, 5, 8]]
+        );
+    }
+}
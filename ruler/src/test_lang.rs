@@ -0,0 +1,78 @@
+//! A small arithmetic language for unit tests that need a [SynthLanguage].
+use crate::map;
+use crate::*;
+use egg::define_language;
+
+define_language! {
+    #[derive(Serialize, Deserialize)]
+    pub(crate) enum Math {
+        "+" = Add([Id; 2]),
+        "-" = Sub([Id; 2]),
+        "-" = Neg([Id; 1]),
+        "*" = Mul([Id; 2]),
+        Num(i64),
+        Var(Symbol),
+    }
+}
+
+impl SynthLanguage for Math {
+    type Constant = i64;
+    type Config = ();
+
+    fn eval<'a, F>(&'a self, cvec_len: usize, mut get: F) -> CVec<Self>
+    where
+        F: FnMut(&'a Id) -> &'a [Option<Self::Constant>],
+    {
+        match self {
+            Math::Add([a, b]) => map!(get, a, b => a.checked_add(*b)),
+            Math::Sub([a, b]) => map!(get, a, b => a.checked_sub(*b)),
+            Math::Neg([a]) => map!(get, a => a.checked_neg()),
+            Math::Mul([a, b]) => map!(get, a, b => a.checked_mul(*b)),
+            Math::Num(n) => vec![Some(*n); cvec_len],
+            Math::Var(_) => vec![],
+        }
+    }
+
+    fn to_var(&self) -> Option<Symbol> {
+        match self {
+            Math::Var(sym) => Some(*sym),
+            _ => None,
+        }
+    }
+
+    fn mk_var(sym: Symbol) -> Self {
+        Math::Var(sym)
+    }
+
+    fn to_constant(&self) -> Option<&Self::Constant> {
+        match self {
+            Math::Num(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn mk_constant(c: Self::Constant) -> Option<Self> {
+        Some(Math::Num(c))
+    }
+
+    fn init_synth(_synth: &mut Synthesizer<Self, Uninit>) {
+        unreachable!("the test language doesn't synthesize")
+    }
+
+    fn make_layer<'a>(
+        _ids: Vec<Id>,
+        _synth: &'a Synthesizer<Self, Init>,
+        _iter: usize,
+    ) -> Box<dyn Iterator<Item = Self> + 'a> {
+        unreachable!("the test language doesn't synthesize")
+    }
+
+    fn is_valid(
+        _synth: &Synthesizer<Self, Init>,
+        _rng: &mut Pcg32,
+        _lhs: &Pattern<Self>,
+        _rhs: &Pattern<Self>,
+    ) -> ValidationResult {
+        unreachable!("the test language doesn't synthesize")
+    }
+}
@@ -1,3 +1,6 @@
+use serde::Serialize;
+use std::{fs, io, path::Path};
+
 /// Helper function to cross product a list of values `ts` across `n` variables.
 pub fn self_product<T: Clone>(ts: &[T], n: usize) -> Vec<Vec<T>> {
     (0..n)
@@ -17,6 +20,28 @@ pub fn self_product<T: Clone>(ts: &[T], n: usize) -> Vec<Vec<T>> {
         .collect()
 }
 
+/// Write `value` as pretty printed JSON to a temporary file next to `path`
+/// and then move it into place, so that readers never see a partially
+/// written file. If writing fails, `path` is left as it was.
+pub fn write_json_atomic<T: Serialize + ?Sized>(
+    path: &Path,
+    value: &T,
+) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let write = || -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut file, value)?;
+        io::Write::flush(&mut file)?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -32,4 +57,31 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn test_write_json_atomic() {
+        let dir = std::env::temp_dir()
+            .join(format!("ruler-write-json-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.json");
+        let read = || -> Vec<u32> {
+            serde_json::from_reader(fs::File::open(&path).unwrap()).unwrap()
+        };
+
+        write_json_atomic(&path, &[1, 2, 3]).unwrap();
+        assert_eq!(read(), vec![1, 2, 3]);
+        write_json_atomic(&path, &[4]).unwrap();
+        assert_eq!(read(), vec![4]);
+
+        // json objects need string keys, so this fails part way through and
+        // leaves the earlier file in place
+        let bad: std::collections::BTreeMap<(u32, u32), u32> =
+            std::iter::once(((0, 0), 0)).collect();
+        assert!(write_json_atomic(&path, &bad).is_err());
+        assert_eq!(read(), vec![4]);
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, 1, "the temporary file wasn't removed");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
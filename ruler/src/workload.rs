@@ -0,0 +1,265 @@
+//! Workload-driven term enumeration.
+//!
+//! Instead of applying every operator to every combination of e-classes, a
+//! workload lists the shapes of terms that we care about as patterns, for
+//! example `(+ ?a (* ?b ?c))` for a multiply-accumulate. Every pattern
+//! variable is a hole, so the synthesizer only adds instances of those
+//! shapes.
+//!
+//! A workload can also be a grammar. An entry `mac := (+ ?a (* ?b ?c))` is
+//! a production of the nonterminal `mac`, and a hole named after a
+//! nonterminal, like `?mac` in `mac := (+ ?mac (* ?b ?c))`, is only filled
+//! with e-classes that contain an instance of one of its productions. This
+//! grows chains of multiply-accumulates instead of every term of that
+//! height. All other holes are filled with every e-class whose smallest
+//! term is at most [SynthParams::workload_hole_depth] deep, or with every
+//! e-class if that isn't set.
+use egg::{AstDepth, Searcher};
+
+use crate::*;
+
+/// A term to add to the egraph while enumerating a layer.
+pub(crate) enum LayerTerm<L: SynthLanguage> {
+    /// A single node produced by [SynthLanguage::make_layer].
+    Node(L),
+    /// Template `template` of the workload, with hole `i` filled by
+    /// `fill[i]`.
+    Template { template: usize, fill: Vec<Id> },
+}
+
+struct Template<L: SynthLanguage> {
+    pattern: Pattern<L>,
+    holes: Vec<Var>,
+    /// The nonterminal that this template is a production of, if any.
+    nonterminal: Option<String>,
+}
+
+/// Split a workload entry into the nonterminal it defines, if it is a
+/// production like `name := pattern`, and its pattern.
+fn parse_entry(entry: &str) -> (Option<&str>, &str) {
+    match entry.split_once(":=") {
+        Some((name, pattern))
+            if !name.trim().is_empty()
+                && name
+                    .trim()
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-') =>
+        {
+            (Some(name.trim()), pattern.trim())
+        }
+        _ => (None, entry.trim()),
+    }
+}
+
+/// The term templates to enumerate, parsed from
+/// [SynthParams::workload](crate::SynthParams::workload).
+pub(crate) struct Workload<L: SynthLanguage> {
+    templates: Vec<Template<L>>,
+}
+
+impl<L: SynthLanguage> Workload<L> {
+    /// Parse the templates in `workload`. Returns `None` if there aren't any,
+    /// in which case terms are enumerated with [SynthLanguage::make_layer],
+    /// and an error if a template isn't a valid pattern.
+    pub fn new(workload: &[String]) -> Result<Option<Self>, String> {
+        if workload.is_empty() {
+            return Ok(None);
+        }
+        let templates = workload
+            .iter()
+            .map(|s| {
+                let (nonterminal, pattern) = parse_entry(s);
+                let pattern: Pattern<L> = pattern.parse().map_err(|e| {
+                    format!("Failed to parse workload template {s:?}: {e:?}")
+                })?;
+                let holes = pattern.vars();
+                Ok(Template {
+                    pattern,
+                    holes,
+                    nonterminal: nonterminal.map(|n| n.to_string()),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Some(Self { templates }))
+    }
+
+    /// The e-classes of `egraph` that contain an instance of a production of
+    /// each nonterminal.
+    fn nonterminals(
+        &self,
+        egraph: &EGraph<L, SynthAnalysis>,
+    ) -> HashMap<String, Vec<Id>> {
+        let mut members: HashMap<String, Vec<Id>> = HashMap::default();
+        for t in &self.templates {
+            if let Some(name) = &t.nonterminal {
+                let ids = members.entry(name.clone()).or_default();
+                ids.extend(
+                    t.pattern.search(egraph).into_iter().map(|m| m.eclass),
+                );
+            }
+        }
+        for ids in members.values_mut() {
+            ids.sort();
+            ids.dedup();
+        }
+        members
+    }
+
+    /// Every instance of every template whose holes are filled with `ids`,
+    /// restricted by the grammar and by `max_depth` as described in the
+    /// [module docs](self). Like [SynthLanguage::make_layer], instances
+    /// whose holes are all filled with exact e-classes are skipped, since
+    /// they only compute constants.
+    pub fn layer<'a>(
+        &'a self,
+        ids: Vec<Id>,
+        egraph: &'a EGraph<L, SynthAnalysis>,
+        max_depth: Option<usize>,
+    ) -> impl Iterator<Item = LayerTerm<L>> + 'a {
+        let plain: Vec<Id> = match max_depth {
+            Some(depth) => {
+                let extractor = Extractor::new(egraph, AstDepth);
+                ids.into_iter()
+                    .filter(|id| extractor.find_best_cost(*id) <= depth)
+                    .collect()
+            }
+            None => ids,
+        };
+        let nonterminals = self.nonterminals(egraph);
+
+        self.templates.iter().enumerate().flat_map(move |(i, t)| {
+            let choices: Vec<Vec<Id>> = t
+                .holes
+                .iter()
+                .map(|hole| {
+                    let name = hole.to_string();
+                    match nonterminals.get(name.trim_start_matches('?')) {
+                        Some(members) => members.clone(),
+                        None => plain.clone(),
+                    }
+                })
+                .collect();
+            let fills: Box<dyn Iterator<Item = Vec<Id>> + 'a> = if choices
+                .is_empty()
+            {
+                Box::new(std::iter::once(vec![]))
+            } else {
+                Box::new(choices.into_iter().multi_cartesian_product().filter(
+                    move |fill| !fill.iter().all(|id| egraph[*id].data.exact),
+                ))
+            };
+            fills.map(move |fill| LayerTerm::Template { template: i, fill })
+        })
+    }
+
+    /// Add an instance of a template to `egraph`, returning the id of its
+    /// root.
+    pub fn add(
+        &self,
+        egraph: &mut EGraph<L, SynthAnalysis>,
+        template: usize,
+        fill: &[Id],
+    ) -> Id {
+        let t = &self.templates[template];
+        let mut ids: Vec<Id> = vec![];
+        for node in t.pattern.ast.as_ref() {
+            let id = match node {
+                ENodeOrVar::Var(v) => {
+                    let hole = t.holes.iter().position(|h| h == v).unwrap();
+                    fill[hole]
+                }
+                ENodeOrVar::ENode(n) => {
+                    egraph.add(n.clone().map_children(|c| ids[usize::from(c)]))
+                }
+            };
+            ids.push(id);
+        }
+        *ids.last().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_lang::Math;
+
+    fn workload(templates: &[&str]) -> Workload<Math> {
+        let templates: Vec<String> =
+            templates.iter().map(|t| t.to_string()).collect();
+        Workload::new(&templates).unwrap().unwrap()
+    }
+
+    fn egraph() -> (EGraph<Math, SynthAnalysis>, Vec<Id>) {
+        let mut egraph = EGraph::new(SynthAnalysis::new(1));
+        let a = egraph.add(Math::Var("a".into()));
+        let b = egraph.add(Math::Var("b".into()));
+        let ab = egraph.add(Math::Mul([a, b]));
+        (egraph, vec![a, b, ab])
+    }
+
+    fn fills(terms: impl Iterator<Item = LayerTerm<Math>>) -> Vec<Vec<Id>> {
+        terms
+            .map(|term| match term {
+                LayerTerm::Template { fill, .. } => fill,
+                LayerTerm::Node(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        assert_eq!(parse_entry("mac := (+ ?a ?b)"), (Some("mac"), "(+ ?a ?b)"));
+        assert_eq!(parse_entry(" (+ ?a ?b) "), (None, "(+ ?a ?b)"));
+        // not a nonterminal name, so the whole entry is the pattern
+        assert_eq!(parse_entry("a b := ?c"), (None, "a b := ?c"));
+    }
+
+    #[test]
+    fn test_bad_template() {
+        assert!(Workload::<Math>::new(&[]).unwrap().is_none());
+        let bad = vec!["(+ ?a ?b)".to_string(), "(+ ?a".to_string()];
+        let err = Workload::<Math>::new(&bad).err().unwrap();
+        assert!(err.contains("(+ ?a"), "{}", err);
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let workload = workload(&["(+ ?x ?y)"]);
+        let (mut egraph, ids) = egraph();
+        let fills = fills(workload.layer(ids.clone(), &egraph, None));
+        assert_eq!(fills.len(), 9);
+
+        // a hole depth of one leaves out `(* a b)`
+        let shallow = workload.layer(ids, &egraph, Some(1));
+        assert_eq!(shallow.count(), 4);
+
+        for fill in &fills {
+            workload.add(&mut egraph, 0, fill);
+        }
+        egraph.rebuild();
+        let expr = "(+ (* a b) a)".parse().unwrap();
+        assert!(egraph.lookup_expr(&expr).is_some());
+    }
+
+    #[test]
+    fn test_expand_grammar() {
+        let workload =
+            workload(&["mac := (+ ?mac (* ?b ?c))", "mac := (* ?b ?c)"]);
+        let (egraph, ids) = egraph();
+        let ab = ids[2];
+        let terms: Vec<_> = workload.layer(ids, &egraph, None).collect();
+        assert_eq!(terms.len(), 9 + 9);
+
+        // `?mac` is only filled with the e-class of `(* a b)`
+        let mac = workload.templates[0]
+            .holes
+            .iter()
+            .position(|h| h.to_string() == "?mac")
+            .unwrap();
+        for term in terms {
+            if let LayerTerm::Template { template: 0, fill } = term {
+                assert_eq!(fill[mac], ab);
+            }
+        }
+    }
+}